@@ -14,4 +14,10 @@ ssh-keygen -y -f ~/.ssh/id_ed25519 |awk '{print $1" "$2" enokey@docker"}'> ~/.ss
 chmod 644 ~/.ssh/id_ed25519.pub
 chown -R . enokey
 su enokey
-exec "$@" --admin-servers "$ADMIN_SERVERS" --admin-psk "$ADMIN_PSK" --user-servers "$USER_SERVERS" --user-psk "$USER_PSK"
+exec "$@" --admin-servers "$ADMIN_SERVERS" --admin-psk "$ADMIN_PSK" --user-servers "$USER_SERVERS" --user-psk "$USER_PSK" --gitlab-tokens "$GITLAB_TOKENS" --gitea-instances "$GITEA_INSTANCES" \
+    --ldap-url "$LDAP_URL" --ldap-base-dn "$LDAP_BASE_DN" --ldap-bind-dn "$LDAP_BIND_DN" \
+    --ldap-bind-password "$LDAP_BIND_PASSWORD" --ldap-filter "$LDAP_FILTER" \
+    --oidc-issuer "$OIDC_ISSUER" --oidc-client-id "$OIDC_CLIENT_ID" --oidc-client-secret "$OIDC_CLIENT_SECRET" \
+    --oidc-redirect-url "$OIDC_REDIRECT_URL" --oidc-provider "$OIDC_PROVIDER" --oidc-admin-group "$OIDC_ADMIN_GROUP" \
+    --rate-limit "$RATE_LIMIT" --max-failures "$MAX_FAILURES" --log-level "$LOG_LEVEL" --metrics-token "$METRICS_TOKEN" --known-hosts-pattern "$KNOWN_HOSTS_PATTERN" --cert-validity "$CERT_VALIDITY" \
+    ${VERIFY_PROVIDERS:+--verify-providers} ${VERIFY_RAW_KEYS:+--verify-raw-keys} ${REQUIRE_APPROVAL:+--require-approval} ${SIGN_AUDIT:+--sign-audit} ${LOG_JSON:+--log-json}
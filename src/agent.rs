@@ -0,0 +1,364 @@
+extern crate dirs;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use rocket::http::Status;
+use rocket_contrib::json::Json;
+use ssh_key::{HashAlg, LineEnding, PrivateKey, PublicKey, SshSig};
+
+use authorized_keys_file;
+use cli::Flags;
+use deploy;
+use error::EnokeysError;
+use hosts::{Host, HostAuth};
+use storage;
+
+const NAMESPACE: &str = "enokey-agent";
+pub const TOKEN_ENV: &str = "ENOKEY_HOST_TOKEN";
+pub const DEFAULT_INTERVAL: u64 = 300;
+pub const DEFAULT_CACHE_DIR: &str = "/var/cache/enokey";
+/// Seconds an answer is accepted after it was signed, to keep old answers from being replayed.
+const MAX_AGE: i64 = 300;
+
+/// A group's authorized_keys for a host, signed with the deploy key.
+#[derive(Serialize, Deserialize)]
+pub struct SignedKeys {
+    pub group: String,
+    pub host: String,
+    /// When the answer was signed, in seconds since the epoch.
+    pub issued: i64,
+    pub authorized_keys: String,
+    pub signature: String,
+}
+
+impl SignedKeys {
+    /// What the signature covers: the keys bound to the group, the host and the time.
+    fn payload(&self) -> Result<Vec<u8>, EnokeysError> {
+        Ok(serde_json::to_vec(&(
+            &self.group,
+            &self.host,
+            self.issued,
+            &self.authorized_keys,
+        ))?)
+    }
+
+    fn sign(&mut self, key: &PrivateKey) -> Result<(), EnokeysError> {
+        self.signature = key
+            .sign(NAMESPACE, HashAlg::Sha512, &self.payload()?)?
+            .to_pem(LineEnding::LF)?;
+        Ok(())
+    }
+
+    /// Checks the signature and that the keys are the current ones of `host` in `group`.
+    fn verify(
+        &self,
+        key: &PublicKey,
+        host: &str,
+        group: &str,
+        now: i64,
+    ) -> Result<(), EnokeysError> {
+        let signature = SshSig::from_pem(&self.signature)?;
+        if key.verify(NAMESPACE, &self.payload()?, &signature).is_err() {
+            return Err(EnokeysError::InvalidSignature);
+        }
+        if self.host != host || self.group != group {
+            return Err(EnokeysError::InvalidData(format!(
+                "ENOKEY answered for {}:{}",
+                &self.host, &self.group
+            )));
+        }
+        if (now - self.issued).abs() > MAX_AGE {
+            return Err(EnokeysError::InvalidData(format!(
+                "ENOKEY's answer was signed {} seconds ago",
+                now - self.issued
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn signed_keys(host: Host) -> Result<Json<SignedKeys>, Status> {
+    let keys =
+        fs::read_to_string(authorized_keys_file(host.admin())).map_err(|_| Status::NotFound)?;
+    let mut signed = SignedKeys {
+        group: host.group,
+        host: host.name,
+        issued: Utc::now().timestamp(),
+        authorized_keys: keys,
+        signature: String::new(),
+    };
+    PrivateKey::read_openssh_file(&storage::deploy_key_path())
+        .map_err(EnokeysError::from)
+        .and_then(|key| signed.sign(&key))
+        .map_err(|e| {
+            error!(
+                "Could not sign authorized_keys for {}: {:?}",
+                &signed.host, e
+            );
+            Status::InternalServerError
+        })?;
+    Ok(Json(signed))
+}
+
+/// The authorized_keys last generated for the host's group.
+#[get("/agent/authorized_keys")]
+pub fn authorized_keys_get(host: HostAuth) -> Result<Json<SignedKeys>, Status> {
+    debug!("Host {} checked in", &host.0.name);
+    signed_keys(host.0)
+}
+
+/// The keys sshd's AuthorizedKeysCommand accepts for the local `user`, if the host
+/// lets the user in. `host` guards against a token copied to another host.
+#[get("/agent/authorized_keys/<user>?<host>")]
+pub fn user_authorized_keys_get(
+    user: String,
+    host: Option<String>,
+    auth: HostAuth,
+) -> Result<Json<SignedKeys>, Status> {
+    let auth = auth.0;
+    if host.is_some_and(|host| host != auth.name) {
+        return Err(Status::Forbidden);
+    }
+    if !auth.users.contains(&user) {
+        return Err(Status::NotFound);
+    }
+    debug!("Host {} asked for the keys of {}", &auth.name, &user);
+    signed_keys(auth)
+}
+
+/// The host token from `ENOKEY_HOST_TOKEN` or the `--host-token-file`.
+fn token(flags: &Flags) -> Result<String, EnokeysError> {
+    match flags.token_file {
+        Some(ref path) => Ok(fs::read_to_string(path)?.trim().to_string()),
+        None => env::var(TOKEN_ENV).map_err(|_| {
+            EnokeysError::InvalidData(format!("{} or --host-token-file is required", TOKEN_ENV))
+        }),
+    }
+}
+
+/// The host and group the token was created for, as `HOST:GROUP`.
+struct Expected {
+    host: String,
+    group: String,
+}
+
+impl Expected {
+    fn parse(arg: Option<&String>) -> Result<Expected, EnokeysError> {
+        match arg.and_then(|a| a.split_once(':')) {
+            Some((host, group)) if !host.is_empty() && !group.is_empty() => Ok(Expected {
+                host: host.to_string(),
+                group: group.to_string(),
+            }),
+            _ => Err(EnokeysError::InvalidData(
+                "HOST:GROUP of the host token is required".to_string(),
+            )),
+        }
+    }
+}
+
+/// Fetches `path` from the server and checks the signature, host, group and age of the
+/// answer, `None` if the server has no keys.
+fn fetch(
+    url: &str,
+    path: &str,
+    token: &str,
+    key: &PublicKey,
+    expected: &Expected,
+) -> Result<Option<SignedKeys>, EnokeysError> {
+    let mut res = reqwest::Client::new()
+        .get(&format!("{}{}", url.trim_end_matches('/'), path))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()?;
+    if res.status() == 404 {
+        return Ok(None);
+    }
+    if res.status() == 403 {
+        return Err(EnokeysError::InvalidCredentials);
+    }
+    if res.status() != 200 {
+        return Err(EnokeysError::InvalidData(format!(
+            "ENOKEY answered {}",
+            res.status()
+        )));
+    }
+    let keys: SignedKeys = res.json()?;
+    keys.verify(key, &expected.host, &expected.group, Utc::now().timestamp())?;
+    Ok(Some(keys))
+}
+
+fn update(
+    url: &str,
+    token: &str,
+    key: &PublicKey,
+    expected: &Expected,
+    path: &Path,
+) -> Result<(), EnokeysError> {
+    let keys = fetch(url, "/agent/authorized_keys", token, key, expected)?.ok_or_else(|| {
+        EnokeysError::InvalidData("ENOKEY has no authorized_keys for the group".to_string())
+    })?;
+    if fs::read_to_string(path).ok().as_ref() == Some(&keys.authorized_keys) {
+        debug!("{} is up to date", path.display());
+        return Ok(());
+    }
+    deploy::install(path, &keys.authorized_keys)?;
+    info!(
+        "Installed {} keys of {} to {}",
+        keys.authorized_keys
+            .lines()
+            .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+            .count(),
+        &keys.group,
+        path.display()
+    );
+    Ok(())
+}
+
+/// Runs the `agent` or `authorized-keys-command` command, both verify the server's
+/// responses with the deploy key PUBKEY.
+pub fn run(command: &str, args: &[String], flags: &Flags) -> Result<(), EnokeysError> {
+    let (url, key) = match (args.first(), args.get(1)) {
+        (Some(url), Some(key)) => (url, key),
+        _ => {
+            return Err(EnokeysError::InvalidData(format!(
+                "usage: {} URL PUBKEY ...",
+                command
+            )))
+        }
+    };
+    let token = token(flags)?;
+    let key = PublicKey::read_openssh_file(Path::new(key))?;
+    if command == "authorized-keys-command" {
+        return authorized_keys_command(url, &token, &key, &args[2..], flags);
+    }
+    agent(url, &token, &key, &args[2..], flags.interval)
+}
+
+/// `agent URL PUBKEY HOST:GROUP [FILE]`: keeps FILE in sync with the host's
+/// authorized_keys on the server at URL. Runs once if `interval` is 0.
+fn agent(
+    url: &str,
+    token: &str,
+    key: &PublicKey,
+    args: &[String],
+    interval: u64,
+) -> Result<(), EnokeysError> {
+    let expected = Expected::parse(args.first())?;
+    let path = match args.get(1) {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let mut path = dirs::home_dir().ok_or(EnokeysError::InvalidEnvironmentError)?;
+            path.push(".ssh");
+            path.push("authorized_keys");
+            path
+        }
+    };
+    if interval == 0 {
+        return update(url, token, key, &expected, &path);
+    }
+    loop {
+        if let Err(e) = update(url, token, key, &expected, &path) {
+            error!("Could not update {}: {:?}", path.display(), e);
+        }
+        thread::sleep(Duration::from_secs(interval));
+    }
+}
+
+/// `authorized-keys-command URL PUBKEY USER HOST:GROUP`: prints the keys of the local USER
+/// for sshd. The last answer is kept in the cache directory and printed while the
+/// server is unreachable.
+fn authorized_keys_command(
+    url: &str,
+    token: &str,
+    key: &PublicKey,
+    args: &[String],
+    flags: &Flags,
+) -> Result<(), EnokeysError> {
+    let user = match args.first() {
+        // sshd passes the name of the user logging in, keep it out of paths
+        Some(user) if !user.is_empty() && !user.contains('/') && !user.starts_with('.') => user,
+        _ => return Err(EnokeysError::InvalidData("invalid user".to_string())),
+    };
+    let expected = Expected::parse(args.get(1))?;
+    let path = format!("/agent/authorized_keys/{}?host={}", user, &expected.host);
+    let cache = Path::new(
+        flags
+            .cache_dir
+            .as_ref()
+            .map_or(DEFAULT_CACHE_DIR, |d| d.as_str()),
+    )
+    .join(format!("{}.authorized_keys", user));
+    match fetch(url, &path, token, key, &expected) {
+        Ok(Some(keys)) => {
+            print!("{}", &keys.authorized_keys);
+            if let Err(e) = deploy::install(&cache, &keys.authorized_keys) {
+                warn!("Could not cache the keys of {}: {:?}", user, e);
+            }
+        }
+        Ok(None) => {
+            debug!("ENOKEY has no keys for {}", user);
+            let _ = fs::remove_file(&cache);
+        }
+        // a revoked token must not keep working from the cache
+        Err(EnokeysError::InvalidCredentials) => {
+            warn!("ENOKEY refused the host token");
+            let _ = fs::remove_file(&cache);
+        }
+        Err(e) => {
+            warn!(
+                "Could not fetch the keys of {}, using the cache: {:?}",
+                user, e
+            );
+            if let Ok(keys) = fs::read_to_string(&cache) {
+                print!("{}", keys);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::rand_core::OsRng;
+    use ssh_key::Algorithm;
+
+    fn signed(key: &PrivateKey) -> SignedKeys {
+        let mut keys = SignedKeys {
+            group: "user".to_string(),
+            host: "box1".to_string(),
+            issued: 1000,
+            authorized_keys: "ssh-ed25519 AAAA alice\n".to_string(),
+            signature: String::new(),
+        };
+        keys.sign(key).unwrap();
+        keys
+    }
+
+    #[test]
+    fn answers_are_bound_to_host_group_and_time() {
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let public = key.public_key();
+        let keys = signed(&key);
+        assert!(keys.verify(public, "box1", "user", 1000 + MAX_AGE).is_ok());
+        assert!(keys.verify(public, "box2", "user", 1000).is_err());
+        assert!(keys.verify(public, "box1", "admin", 1000).is_err());
+        assert!(keys.verify(public, "box1", "user", 1001 + MAX_AGE).is_err());
+
+        let mut moved = signed(&key);
+        moved.host = "box2".to_string();
+        assert!(matches!(
+            moved.verify(public, "box2", "user", 1000),
+            Err(EnokeysError::InvalidSignature)
+        ));
+        let mut replayed = signed(&key);
+        replayed.issued = 5000;
+        assert!(matches!(
+            replayed.verify(public, "box1", "user", 5000),
+            Err(EnokeysError::InvalidSignature)
+        ));
+    }
+}
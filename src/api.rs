@@ -0,0 +1,948 @@
+use std::collections::HashMap;
+use std::fs;
+
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::status::Custom;
+use rocket::response::{self, Responder};
+use rocket::Route;
+use rocket_contrib::json::{Json, JsonError, JsonValue};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{Map, Value};
+use ssh_key::{HashAlg, PublicKey};
+
+use audit;
+use authorized_keys_file;
+use ca::{self, HostCertificate, Issued};
+use challenge;
+use deploy::HostOutcome;
+use deploy_group;
+use error::EnokeysError;
+use invitations;
+use ldap;
+use ratelimit::RateLimit;
+use roles::{self, Principal, Role};
+use scraper;
+use storage;
+use tokens;
+use use_invitation;
+use Context;
+use Target;
+use CONFIG;
+use USERNAME_REGEX;
+
+pub const PREFIX: &str = "/api/v1";
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        groups_get,
+        destinations_get,
+        keys_get,
+        submissions_get,
+        submissions_post,
+        submissions_verify,
+        submissions_review,
+        deploy_post,
+        certificates_get,
+        certificates_post,
+        host_certificates_post
+    ]
+}
+
+pub fn is_api(request: &Request) -> bool {
+    request.uri().path().starts_with(PREFIX)
+}
+
+#[derive(Serialize, JsonSchema)]
+struct ErrorBody {
+    error: String,
+}
+
+/// An error answered with its status and a JSON body `{"error": "..."}`.
+#[derive(Debug)]
+pub struct ApiError(pub Status, pub String);
+
+impl From<EnokeysError> for ApiError {
+    fn from(error: EnokeysError) -> ApiError {
+        let status = error.status();
+        if status == Status::InternalServerError {
+            // details like paths are only for the log
+            error!("API request failed: {:?}", error);
+            return ApiError(status, "internal error".to_string());
+        }
+        ApiError(status, format!("{:?}", error))
+    }
+}
+
+impl<'a> From<JsonError<'a>> for ApiError {
+    fn from(error: JsonError<'a>) -> ApiError {
+        match error {
+            JsonError::Io(e) => ApiError(Status::BadRequest, format!("{:?}", e)),
+            JsonError::Parse(_, e) => ApiError(Status::UnprocessableEntity, e.to_string()),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        Custom(self.0, Json(ErrorBody { error: self.1 })).respond_to(request)
+    }
+}
+
+type ApiResult<T> = Result<Custom<Json<T>>, ApiError>;
+
+fn ok<T>(value: T) -> ApiResult<T> {
+    Ok(Custom(Status::Ok, Json(value)))
+}
+
+/// The principal an `Authorization: Bearer <token>` header belongs to.
+pub struct ApiPrincipal(pub Principal);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ApiPrincipal {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ApiPrincipal, ()> {
+        let token = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "));
+        let name = match token.and_then(tokens::principal) {
+            Some(name) => name,
+            None => {
+                if token.is_some() {
+                    if let Outcome::Success(limit) = request.guard::<RateLimit>() {
+                        limit.failure();
+                    }
+                }
+                return Outcome::Failure((Status::Unauthorized, ()));
+            }
+        };
+        match Principal::load(name) {
+            Ok(principal) => Outcome::Success(ApiPrincipal(principal)),
+            Err(_) => Outcome::Failure((Status::InternalServerError, ())),
+        }
+    }
+}
+
+/// Checks that the principal has at least `role` in `group`, returns whether it is the admin group.
+fn require(principal: &Principal, group: &str, role: Role) -> Result<bool, ApiError> {
+    if !roles::GROUPS.contains(&group) {
+        return Err(ApiError(
+            Status::NotFound,
+            format!("unknown group: {}", group),
+        ));
+    }
+    if !principal.has_role(group, role) {
+        return Err(ApiError(
+            Status::Forbidden,
+            format!("{} role required in {}", role, group),
+        ));
+    }
+    Ok(group == roles::GROUPS[0])
+}
+
+#[derive(Serialize, JsonSchema)]
+struct GroupInfo {
+    name: &'static str,
+    /// The principal's role in the group, if any.
+    role: Option<String>,
+    require_approval: bool,
+}
+
+#[get("/groups")]
+fn groups_get(principal: ApiPrincipal) -> ApiResult<Vec<GroupInfo>> {
+    let config = &*CONFIG.lock().unwrap();
+    ok(roles::GROUPS
+        .iter()
+        .map(|&name| GroupInfo {
+            name,
+            role: principal.0.roles.get(name).map(|r| r.to_string()),
+            require_approval: config.require_approval,
+        })
+        .collect())
+}
+
+#[derive(Serialize, JsonSchema)]
+struct DestinationInfo {
+    group: &'static str,
+    name: String,
+    /// `ssh` or the kind of a local target, e.g. `k8s-secret`.
+    kind: &'static str,
+    address: String,
+    user: String,
+    port: u16,
+}
+
+#[get("/destinations")]
+fn destinations_get(principal: ApiPrincipal) -> ApiResult<Vec<DestinationInfo>> {
+    let config = &*CONFIG.lock().unwrap();
+    let mut destinations = vec![];
+    for group in principal.0.groups(Role::Reviewer) {
+        let group_destinations = if group == roles::GROUPS[0] {
+            &config.admin_destinations
+        } else {
+            &config.user_destinations
+        };
+        destinations.extend(group_destinations.iter().map(|d| DestinationInfo {
+            group,
+            name: d.destination_name.clone(),
+            kind: d.kind.name(),
+            address: d.address.clone(),
+            user: d.userauth_agent.clone(),
+            port: d.port,
+        }));
+    }
+    ok(destinations)
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Key {
+    line: String,
+    fingerprint: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct GroupKeys {
+    group: &'static str,
+    keys: Vec<Key>,
+}
+
+/// The generated authorized_keys of the principal's reviewer groups.
+#[get("/keys")]
+fn keys_get(principal: ApiPrincipal) -> ApiResult<Vec<GroupKeys>> {
+    let config = &*CONFIG.lock().unwrap();
+    storage::generate_authorized_key_files(config)?;
+    let mut groups = vec![];
+    for group in principal.0.groups(Role::Reviewer) {
+        let content = fs::read_to_string(authorized_keys_file(group == roles::GROUPS[0]))
+            .map_err(EnokeysError::from)?;
+        groups.push(GroupKeys {
+            group,
+            keys: content
+                .lines()
+                .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+                .map(|line| Key {
+                    line: line.to_string(),
+                    fingerprint: PublicKey::from_openssh(line.trim())
+                        .ok()
+                        .map(|k| k.fingerprint(HashAlg::Sha256).to_string()),
+                })
+                .collect(),
+        });
+    }
+    ok(groups)
+}
+
+#[derive(Serialize, JsonSchema)]
+struct PendingSubmission {
+    group: &'static str,
+    raw: bool,
+    entry: String,
+}
+
+/// The pending submissions of the principal's reviewer groups.
+#[get("/submissions")]
+fn submissions_get(principal: ApiPrincipal) -> ApiResult<Vec<PendingSubmission>> {
+    let mut submissions = vec![];
+    for group in principal.0.groups(Role::Reviewer) {
+        for pending in storage::pending_entries(group == roles::GROUPS[0])? {
+            submissions.push(PendingSubmission {
+                group,
+                raw: pending.raw,
+                entry: pending.entry,
+            });
+        }
+    }
+    ok(submissions)
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct NewSubmission {
+    group: String,
+    /// A provider like `github`, a Gitea instance, `ldap`, `ldap-group`,
+    /// `<instance>-group` for GitLab groups or `raw`.
+    provider: String,
+    /// The account, or the group for `ldap-group` and GitLab groups.
+    #[serde(default)]
+    user_name: String,
+    /// The public key for `raw` submissions.
+    #[serde(default)]
+    pub_key: String,
+    /// The minimum access level of GitLab group members.
+    access_level: Option<u32>,
+    name: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Submitted {
+    group: String,
+    /// Whether the submission waits for approval by a reviewer.
+    pending: bool,
+    /// Set if the submitter still has to sign the challenge and post it to `submissions/verify`.
+    challenge: Option<challenge::ChallengeContext>,
+}
+
+fn issue_challenge(
+    submission: challenge::Submission,
+    keys: Vec<String>,
+    description: String,
+) -> Option<challenge::ChallengeContext> {
+    Some(challenge::ChallengeContext {
+        nonce: challenge::issue(submission, keys),
+        description,
+        namespace: challenge::NAMESPACE,
+    })
+}
+
+fn submit(
+    config: &Context,
+    submission: &NewSubmission,
+    target: Target,
+) -> Result<Option<challenge::ChallengeContext>, EnokeysError> {
+    let provider = submission.provider.as_str();
+    if provider == "raw" {
+        if config.verify_raw_keys {
+            let pub_key = storage::parse_raw_key(&submission.pub_key)?;
+            return Ok(issue_challenge(
+                challenge::Submission::Raw {
+                    pub_key: pub_key.clone(),
+                    name: submission.name.clone(),
+                    admin: target.admin,
+                    pending: target.pending,
+                    invitation: None,
+                },
+                vec![pub_key],
+                "the submitted raw pubkey".to_string(),
+            ));
+        }
+        storage::handle_raw_submission(
+            &submission.name,
+            &submission.pub_key,
+            target.admin,
+            target.pending,
+        )?;
+        return Ok(None);
+    }
+    if provider == ldap::LDAP || provider == ldap::LDAP_GROUP {
+        if config.ldap.is_none() {
+            return Err(EnokeysError::InvalidProviderError(provider.to_string()));
+        }
+    } else if let Some(instance) = provider.strip_suffix(scraper::GITLAB_GROUP_SUFFIX) {
+        storage::handle_group_submission(
+            instance,
+            &submission.user_name,
+            submission
+                .access_level
+                .unwrap_or(scraper::GITLAB_GUEST_ACCESS),
+            &submission.name,
+            target.admin,
+            target.pending,
+        )?;
+        return Ok(None);
+    } else if !scraper::is_provider(provider, &config.gitea_instances) {
+        return Err(EnokeysError::InvalidProviderError(provider.to_string()));
+    } else if config.verify_providers {
+        let user_name = USERNAME_REGEX
+            .replace_all(&submission.user_name, "")
+            .to_string();
+        let keys = scraper::fetch(&user_name, provider, &config.gitea_instances)?;
+        if keys.is_empty() {
+            return Err(EnokeysError::InvalidData(format!(
+                "no keys found for {} user {:?}",
+                provider, &user_name
+            )));
+        }
+        return Ok(issue_challenge(
+            challenge::Submission::Provider {
+                provider: provider.to_string(),
+                user_name: user_name.clone(),
+                name: submission.name.clone(),
+                admin: target.admin,
+                pending: target.pending,
+                invitation: None,
+            },
+            keys,
+            format!("{} user {:?}", provider, &user_name),
+        ));
+    }
+    storage::handle_submission(
+        provider,
+        &submission.user_name,
+        &submission.name,
+        target.admin,
+        target.pending,
+    )?;
+    Ok(None)
+}
+
+#[post("/submissions", data = "<body>")]
+fn submissions_post(
+    body: Result<Json<NewSubmission>, JsonError>,
+    principal: ApiPrincipal,
+    _limit: RateLimit,
+    client: audit::Client,
+) -> ApiResult<Submitted> {
+    let submission = body?.into_inner();
+    let admin = require(&principal.0, &submission.group, Role::Submitter)?;
+    let config = &*CONFIG.lock().unwrap();
+    let target = Target {
+        admin,
+        pending: config.require_approval
+            && !principal.0.has_role(&submission.group, Role::Reviewer),
+    };
+    let challenge = submit(config, &submission, target)?;
+    let mut record = audit::Record::new(
+        &principal.0.name,
+        &client,
+        "submit",
+        Some(&submission.group),
+        &format!(
+            "{} {:?} ({}){}",
+            &submission.provider,
+            if submission.provider == "raw" {
+                ""
+            } else {
+                &submission.user_name
+            },
+            &submission.name,
+            target.note()
+        ),
+    );
+    if submission.provider == "raw" {
+        record.fingerprints = audit::fingerprints(&submission.pub_key);
+    }
+    audit::append(&record);
+    let status = if challenge.is_some() {
+        Status::Accepted
+    } else {
+        Status::Created
+    };
+    Ok(Custom(
+        status,
+        Json(Submitted {
+            group: submission.group,
+            pending: target.pending,
+            challenge,
+        }),
+    ))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct Verification {
+    challenge: String,
+    signature: String,
+}
+
+// like the form at /verify, the signature authenticates the request
+#[post("/submissions/verify", data = "<body>")]
+fn submissions_verify(
+    body: Result<Json<Verification>, JsonError>,
+    _limit: RateLimit,
+    client: audit::Client,
+) -> ApiResult<Submitted> {
+    let verification = body?.into_inner();
+    let submission = challenge::redeem(&verification.challenge, &verification.signature)?;
+    // the invitation may have been used up while the challenge was open
+    if let Some(token) = submission.invitation() {
+        invitations::check(token)?;
+    }
+    let (who, details, fingerprints, admin, pending) = match submission {
+        challenge::Submission::Provider {
+            provider,
+            user_name,
+            name,
+            admin,
+            pending,
+            invitation,
+        } => {
+            storage::handle_submission(&provider, &user_name, &name, admin, pending)?;
+            if let Some(ref token) = invitation {
+                use_invitation(token, &format!("{}:{} ({})", &provider, &user_name, &name));
+            }
+            (
+                format!("{}:{}", &provider, &user_name),
+                format!("{} ({})", &provider, &name),
+                vec![],
+                admin,
+                pending,
+            )
+        }
+        challenge::Submission::Raw {
+            pub_key,
+            name,
+            admin,
+            pending,
+            invitation,
+        } => {
+            storage::handle_raw_submission(&name, &pub_key, admin, pending)?;
+            if let Some(ref token) = invitation {
+                use_invitation(token, &format!("raw pubkey ({})", &name));
+            }
+            (
+                "raw".to_string(),
+                format!("raw pubkey ({})", &name),
+                audit::fingerprints(&pub_key),
+                admin,
+                pending,
+            )
+        }
+    };
+    let group = roles::group_name(admin);
+    let mut record = audit::Record::new(
+        &who,
+        &client,
+        "verify",
+        Some(group),
+        &format!("{}{}", details, Target { admin, pending }.note()),
+    );
+    record.fingerprints = fingerprints;
+    audit::append(&record);
+    Ok(Custom(
+        Status::Created,
+        Json(Submitted {
+            group: group.to_string(),
+            pending,
+            challenge: None,
+        }),
+    ))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct Review {
+    group: String,
+    raw: bool,
+    entry: String,
+    approve: bool,
+}
+
+#[post("/submissions/review", data = "<body>")]
+fn submissions_review(
+    body: Result<Json<Review>, JsonError>,
+    principal: ApiPrincipal,
+    _limit: RateLimit,
+    client: audit::Client,
+) -> Result<Status, ApiError> {
+    let review = body?.into_inner();
+    let admin = require(&principal.0, &review.group, Role::Reviewer)?;
+    storage::review_entry(admin, review.raw, &review.entry, review.approve)?;
+    let mut record = audit::Record::new(
+        &principal.0.name,
+        &client,
+        if review.approve { "approve" } else { "reject" },
+        Some(&review.group),
+        review.entry.trim(),
+    );
+    record.fingerprints = audit::fingerprints(&review.entry);
+    audit::append(&record);
+    Ok(Status::NoContent)
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct DeployRequest {
+    group: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct HostCertificateRequest {
+    group: String,
+    /// The expected host key fingerprints by destination name, only matching hosts are signed.
+    #[serde(default)]
+    fingerprints: HashMap<String, String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct Deployed {
+    group: String,
+    hosts: Vec<HostOutcome>,
+}
+
+/// Answers `502 Bad Gateway` if any destination failed.
+#[post("/deploy", data = "<body>")]
+fn deploy_post(
+    body: Result<Json<DeployRequest>, JsonError>,
+    principal: ApiPrincipal,
+    _limit: RateLimit,
+    client: audit::Client,
+) -> ApiResult<Deployed> {
+    let request = body?.into_inner();
+    let admin = require(&principal.0, &request.group, Role::Deployer)?;
+    let config = &*CONFIG.lock().unwrap();
+    storage::generate_authorized_key_files(config)?;
+    let hosts = deploy_group(config, admin, &principal.0.name, &client);
+    let status = if hosts.iter().all(|h| h.success) {
+        Status::Ok
+    } else {
+        Status::BadGateway
+    };
+    Ok(Custom(
+        status,
+        Json(Deployed {
+            group: request.group,
+            hosts,
+        }),
+    ))
+}
+
+/// The certificates issued by the CAs of the principal's reviewer groups.
+#[get("/certificates")]
+fn certificates_get(principal: ApiPrincipal) -> ApiResult<Vec<Issued>> {
+    ok(ca::issued(&principal.0.groups(Role::Reviewer))?)
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct CertificateRequest {
+    group: String,
+    /// An approved key of the group in OpenSSH format.
+    public_key: String,
+    /// Defaults to and is capped at the group's configured validity.
+    validity_hours: Option<u64>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct SignedCertificate {
+    /// The certificate in OpenSSH format, to be saved as `<key>-cert.pub`.
+    certificate: String,
+    #[serde(flatten)]
+    issued: Issued,
+}
+
+#[post("/certificates", data = "<body>")]
+fn certificates_post(
+    body: Result<Json<CertificateRequest>, JsonError>,
+    principal: ApiPrincipal,
+    _limit: RateLimit,
+    client: audit::Client,
+) -> ApiResult<SignedCertificate> {
+    let request = body?.into_inner();
+    require(&principal.0, &request.group, Role::Submitter)?;
+    let config = &*CONFIG.lock().unwrap();
+    storage::generate_authorized_key_files(config)?;
+    let (certificate, issued) = ca::sign_user(
+        config,
+        &principal.0.name,
+        &principal.0.groups(Role::Submitter),
+        &request.group,
+        &request.public_key,
+        request.validity_hours,
+    )?;
+    audit::append(&ca::audit_record(&principal.0.name, &client, &issued));
+    Ok(Custom(
+        Status::Created,
+        Json(SignedCertificate {
+            certificate,
+            issued,
+        }),
+    ))
+}
+
+/// Answers `502 Bad Gateway` if any destination could not be signed.
+#[post("/host-certificates", data = "<body>")]
+fn host_certificates_post(
+    body: Result<Json<HostCertificateRequest>, JsonError>,
+    principal: ApiPrincipal,
+    _limit: RateLimit,
+    client: audit::Client,
+) -> ApiResult<Vec<HostCertificate>> {
+    let request = body?.into_inner();
+    let admin = require(&principal.0, &request.group, Role::Deployer)?;
+    let config = &*CONFIG.lock().unwrap();
+    let destinations = if admin {
+        &config.admin_destinations
+    } else {
+        &config.user_destinations
+    };
+    let certificates = ca::sign_hosts(
+        &principal.0.name,
+        &client,
+        &request.group,
+        destinations,
+        &request.fingerprints,
+    );
+    let status = if certificates.iter().all(|c| c.error.is_none()) {
+        Status::Ok
+    } else {
+        Status::BadGateway
+    };
+    Ok(Custom(status, Json(certificates)))
+}
+
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+/// The documentation of a route, found by the name of its handler.
+struct Operation {
+    handler: &'static str,
+    summary: &'static str,
+    /// The least role needed in the requested group, `None` if no token is needed.
+    role: Option<&'static str>,
+    request: Option<SchemaFn>,
+    /// Successful statuses, all answered with the `response` body.
+    success: &'static [u16],
+    response: Option<SchemaFn>,
+    /// Statuses answered with an error body.
+    errors: &'static [u16],
+}
+
+/// The body a handler answers with, `None` if it only answers with a status.
+trait ResponseBody {
+    fn schema() -> Option<SchemaFn>;
+}
+
+impl<T: JsonSchema> ResponseBody for ApiResult<T> {
+    fn schema() -> Option<SchemaFn> {
+        Some(SchemaGenerator::subschema_for::<T>)
+    }
+}
+
+impl ResponseBody for Result<Status, ApiError> {
+    fn schema() -> Option<SchemaFn> {
+        None
+    }
+}
+
+/// The request and response schemas of a handler, derived from its signature so that
+/// they cannot drift from the `Json<T>` it reads. `Args` only tells the arities apart.
+trait Handler<Args> {
+    fn schemas(&self) -> (Option<SchemaFn>, Option<SchemaFn>);
+}
+
+impl<F, A, R: ResponseBody> Handler<(A, R)> for F
+where
+    F: Fn(A) -> R,
+{
+    fn schemas(&self) -> (Option<SchemaFn>, Option<SchemaFn>) {
+        (None, R::schema())
+    }
+}
+
+impl<F, T: JsonSchema, A, B, R: ResponseBody> Handler<(Json<T>, A, B, R)> for F
+where
+    F: Fn(Result<Json<T>, JsonError<'static>>, A, B) -> R,
+{
+    fn schemas(&self) -> (Option<SchemaFn>, Option<SchemaFn>) {
+        (Some(SchemaGenerator::subschema_for::<T>), R::schema())
+    }
+}
+
+impl<F, T: JsonSchema, A, B, C, R: ResponseBody> Handler<(Json<T>, A, B, C, R)> for F
+where
+    F: Fn(Result<Json<T>, JsonError<'static>>, A, B, C) -> R,
+{
+    fn schemas(&self) -> (Option<SchemaFn>, Option<SchemaFn>) {
+        (Some(SchemaGenerator::subschema_for::<T>), R::schema())
+    }
+}
+
+macro_rules! operation {
+    ($handler:ident { $($field:ident: $value:expr,)* }) => {{
+        let (request, response) = $handler.schemas();
+        Operation {
+            handler: stringify!($handler),
+            request,
+            response,
+            $($field: $value,)*
+        }
+    }};
+}
+
+fn operations() -> Vec<Operation> {
+    vec![
+        operation!(groups_get {
+            summary: "The groups and the principal's role in each",
+            role: Some("any"),
+            success: &[200],
+            errors: &[401],
+        }),
+        operation!(destinations_get {
+            summary: "The destinations of the principal's reviewer groups",
+            role: Some("reviewer"),
+            success: &[200],
+            errors: &[401],
+        }),
+        operation!(keys_get {
+            summary: "The generated authorized_keys of the principal's reviewer groups",
+            role: Some("reviewer"),
+            success: &[200],
+            errors: &[401, 500],
+        }),
+        operation!(submissions_get {
+            summary: "The pending submissions of the principal's reviewer groups",
+            role: Some("reviewer"),
+            success: &[200],
+            errors: &[401, 500],
+        }),
+        operation!(submissions_post {
+            summary: "Submit keys, answers 202 with a challenge if they have to be verified",
+            role: Some("submitter"),
+            success: &[201, 202],
+            errors: &[400, 401, 403, 404, 422, 429, 502],
+        }),
+        operation!(submissions_verify {
+            summary: "Complete a submission with the signed challenge",
+            role: None,
+            success: &[201],
+            errors: &[400, 403, 422, 429],
+        }),
+        operation!(submissions_review {
+            summary: "Approve or reject a pending submission",
+            role: Some("reviewer"),
+            success: &[204],
+            errors: &[400, 401, 403, 404, 422, 429],
+        }),
+        operation!(deploy_post {
+            summary: "Deploy the group's authorized_keys, answers 502 if a destination failed",
+            role: Some("deployer"),
+            success: &[200, 502],
+            errors: &[401, 403, 404, 422, 429],
+        }),
+        operation!(certificates_get {
+            summary: "The certificates issued by the CAs of the principal's reviewer groups",
+            role: Some("reviewer"),
+            success: &[200],
+            errors: &[401, 500],
+        }),
+        operation!(certificates_post {
+            summary: "Sign a short-lived user certificate for an approved key of the group",
+            role: Some("submitter"),
+            success: &[201],
+            errors: &[400, 401, 403, 404, 422, 429],
+        }),
+        operation!(host_certificates_post {
+            summary: "Sign host certificates for destinations with confirmed host keys, answers 502 if one was not signed",
+            role: Some("deployer"),
+            success: &[200, 502],
+            errors: &[401, 403, 404, 422, 429],
+        }),
+    ]
+}
+
+fn to_value(schema: Schema) -> Value {
+    serde_json::to_value(schema).unwrap()
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+/// The OpenAPI 3 document of all routes in [`routes`], with the schemas derived
+/// from the request and response types.
+pub fn openapi() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let error = to_value(gen.subschema_for::<ErrorBody>());
+    let operations = operations();
+    let mut paths = Map::new();
+    for route in routes() {
+        let operation = operations
+            .iter()
+            .find(|o| Some(o.handler) == route.name)
+            .unwrap_or_else(|| panic!("undocumented API route {}", route));
+        let mut responses = Map::new();
+        for &status in operation.success {
+            let mut response = json!({ "description": Status::from_code(status).unwrap().reason });
+            if let Some(schema) = operation.response {
+                response["content"] = json_content(to_value(schema(&mut gen)));
+            }
+            responses.insert(status.to_string(), response);
+        }
+        for &status in operation.errors {
+            responses.insert(
+                status.to_string(),
+                json!({
+                    "description": Status::from_code(status).unwrap().reason,
+                    "content": json_content(error.clone()),
+                }),
+            );
+        }
+        let mut value = json!({
+            "operationId": operation.handler,
+            "summary": operation.summary,
+            "responses": responses,
+        });
+        match operation.role {
+            Some(role) => value["description"] = format!("Requires the {} role.", role).into(),
+            None => value["security"] = json!([]),
+        }
+        if let Some(schema) = operation.request {
+            value["requestBody"] = json!({
+                "required": true,
+                "content": json_content(to_value(schema(&mut gen))),
+            });
+        }
+        paths
+            .entry(route.uri.path().to_string())
+            .or_insert_with(|| json!({}))[route.method.as_str().to_lowercase()] = value;
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "ENOKEY",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": PREFIX }],
+        "security": [{ "bearer": [] }],
+        "paths": paths,
+        "components": {
+            "schemas": gen.definitions(),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+            },
+        },
+    })
+}
+
+lazy_static! {
+    static ref OPENAPI: Value = openapi();
+}
+
+#[get("/openapi.json")]
+pub fn openapi_get() -> JsonValue {
+    JsonValue(OPENAPI.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_ref(spec: &Value, path: &str) -> Value {
+        spec["paths"][path]["post"]["requestBody"]["content"]["application/json"]["schema"]["$ref"]
+            .clone()
+    }
+
+    #[test]
+    fn documents_exactly_the_mounted_routes() {
+        let spec = openapi();
+        let routes = routes();
+        for route in &routes {
+            let method = route.method.as_str().to_lowercase();
+            assert!(spec["paths"][route.uri.path()][&method].is_object());
+        }
+        for operation in operations() {
+            assert!(routes.iter().any(|r| r.name == Some(operation.handler)));
+        }
+    }
+
+    #[test]
+    fn schemas_follow_the_handlers() {
+        let spec = openapi();
+        assert_eq!(
+            request_ref(&spec, "/deploy"),
+            "#/components/schemas/DeployRequest"
+        );
+        assert_eq!(
+            request_ref(&spec, "/host-certificates"),
+            "#/components/schemas/HostCertificateRequest"
+        );
+        assert_eq!(
+            request_ref(&spec, "/submissions/verify"),
+            "#/components/schemas/Verification"
+        );
+        assert!(spec["paths"]["/groups"]["get"]["requestBody"].is_null());
+        let review = &spec["paths"]["/submissions/review"]["post"]["responses"]["204"];
+        assert!(review["content"].is_null());
+        let deployed = &spec["paths"]["/deploy"]["post"]["responses"]["200"]["content"]
+            ["application/json"]["schema"]["$ref"];
+        assert_eq!(deployed, "#/components/schemas/Deployed");
+    }
+}
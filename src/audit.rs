@@ -0,0 +1,236 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use sha2::{Digest, Sha256};
+use ssh_key::{HashAlg, LineEnding, PrivateKey, PublicKey, SshSig};
+
+use deploy::HostOutcome;
+use error::EnokeysError;
+use AUDIT_STORAGE;
+
+const NAMESPACE: &str = "enokey-audit";
+
+lazy_static! {
+    static ref SIGNING_KEY: Mutex<Option<PrivateKey>> = Mutex::new(None);
+}
+
+/// The address a request came from, recorded with every audit record.
+pub struct Client(pub Option<IpAddr>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Client {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Client, ()> {
+        Outcome::Success(Client(request.client_ip()))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub time: DateTime<Utc>,
+    pub who: String,
+    pub ip: Option<String>,
+    pub action: String,
+    pub group: Option<String>,
+    pub details: String,
+    pub fingerprints: Vec<String>,
+    pub hosts: Vec<HostOutcome>,
+    /// The hash of the previous record, empty for the first one.
+    #[serde(default)]
+    pub prev_hash: String,
+    /// An SSH signature of this record's hash by the deploy key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl Record {
+    pub fn new(
+        who: &str,
+        client: &Client,
+        action: &str,
+        group: Option<&str>,
+        details: &str,
+    ) -> Record {
+        Record {
+            time: Utc::now(),
+            who: who.to_string(),
+            ip: client.0.map(|ip| ip.to_string()),
+            action: action.to_string(),
+            group: group.map(|g| g.to_string()),
+            details: details.to_string(),
+            fingerprints: vec![],
+            hosts: vec![],
+            prev_hash: String::new(),
+            signature: None,
+        }
+    }
+
+    /// The SHA256 of the record without its signature, base64 encoded.
+    fn hash(&self) -> Result<String, EnokeysError> {
+        let mut record = self.clone();
+        record.signature = None;
+        let digest = Sha256::digest(serde_json::to_string(&record)?.as_bytes());
+        Ok(base64::encode_config(digest, base64::STANDARD_NO_PAD))
+    }
+}
+
+/// Narrows down the records shown on the audit page, empty fields match everything.
+#[derive(Debug, Default, FromForm, Serialize)]
+pub struct Filter {
+    pub who: Option<String>,
+    pub action: Option<String>,
+    pub group: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, record: &Record) -> bool {
+        fn field(filter: &Option<String>, value: Option<&str>) -> bool {
+            match filter {
+                Some(ref filter) if !filter.is_empty() => value == Some(filter.as_str()),
+                _ => true,
+            }
+        }
+        field(&self.who, Some(&record.who))
+            && field(&self.action, Some(&record.action))
+            && field(&self.group, record.group.as_deref())
+    }
+}
+
+/// The SHA256 fingerprints of all public keys in `keys`, one key per line.
+pub fn fingerprints(keys: &str) -> Vec<String> {
+    keys.lines()
+        .filter_map(|line| PublicKey::from_openssh(line.trim()).ok())
+        .map(|key| key.fingerprint(HashAlg::Sha256).to_string())
+        .collect()
+}
+
+/// Signs every following record with the private key at `path`.
+pub fn enable_signing(path: &Path) -> Result<(), EnokeysError> {
+    *SIGNING_KEY.lock().unwrap() = Some(PrivateKey::read_openssh_file(path)?);
+    Ok(())
+}
+
+/// Takes a `flock` on the log, released when the file is closed. The server and CLI
+/// commands append to the same log, so the lock is shared between processes.
+fn lock(file: &File, operation: libc::c_int) -> Result<(), EnokeysError> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Opens the log for reading with a shared lock, `None` if there is no log yet.
+fn open_locked() -> Result<Option<File>, EnokeysError> {
+    let file = match File::open(&*AUDIT_STORAGE) {
+        Ok(file) => file,
+        Err(_) => return Ok(None),
+    };
+    lock(&file, libc::LOCK_SH)?;
+    Ok(Some(file))
+}
+
+fn write(record: &Record) -> Result<(), EnokeysError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(&*AUDIT_STORAGE)?;
+    lock(&file, libc::LOCK_EX)?;
+    // another process may have appended since, the head is read again every time
+    let prev_hash = match BufReader::new(&file).lines().last() {
+        Some(line) => serde_json::from_str::<Record>(&line?)?.hash()?,
+        None => String::new(),
+    };
+    let mut record = record.clone();
+    record.prev_hash = prev_hash;
+    let hash = record.hash()?;
+    if let Some(ref key) = *SIGNING_KEY.lock().unwrap() {
+        record.signature = Some(
+            key.sign(NAMESPACE, HashAlg::Sha512, hash.as_bytes())?
+                .to_pem(LineEnding::LF)?,
+        );
+    }
+    writeln!(file, "{}", serde_json::to_string(&record)?)?;
+    Ok(())
+}
+
+/// Appends the record, chained to the previous one; the log is never rewritten.
+pub fn append(record: &Record) {
+    if let Err(e) = write(record) {
+        error!("Could not write audit record {:?}: {:?}", record, e);
+    }
+}
+
+pub fn list(filter: &Filter) -> Result<Vec<Record>, EnokeysError> {
+    let file = match open_locked()? {
+        Some(file) => file,
+        None => return Ok(vec![]),
+    };
+    let mut records = vec![];
+    for line in BufReader::new(file).lines() {
+        let record: Record = serde_json::from_str(&line?)?;
+        if filter.matches(&record) {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+/// Walks the hash chain and checks the signatures with `key`. Returns the
+/// number of records, or the line of the first broken link and why it is broken.
+pub fn verify(key: Option<&PublicKey>) -> Result<usize, (usize, String)> {
+    let file = match open_locked() {
+        Ok(Some(file)) => file,
+        Ok(None) => return Ok(0),
+        Err(e) => return Err((0, format!("unreadable log: {:?}", e))),
+    };
+    let mut prev_hash = String::new();
+    let mut signed = false;
+    let mut count = 0;
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line_number = i + 1;
+        let record: Record = match line
+            .map_err(EnokeysError::from)
+            .and_then(|l| Ok(serde_json::from_str(&l)?))
+        {
+            Ok(record) => record,
+            Err(e) => return Err((line_number, format!("unreadable record: {:?}", e))),
+        };
+        if record.prev_hash != prev_hash {
+            return Err((
+                line_number,
+                "does not link to the previous record".to_string(),
+            ));
+        }
+        let hash = record
+            .hash()
+            .map_err(|e| (line_number, format!("{:?}", e)))?;
+        match (&record.signature, key) {
+            (Some(ref signature), Some(key)) => {
+                let valid = SshSig::from_pem(signature)
+                    .map(|s| key.verify(NAMESPACE, hash.as_bytes(), &s).is_ok())
+                    .unwrap_or(false);
+                if !valid {
+                    return Err((line_number, "invalid signature".to_string()));
+                }
+                signed = true;
+            }
+            // once signing was enabled, dropping signatures would hide a rewrite
+            (None, _) if signed => {
+                return Err((line_number, "missing signature".to_string()));
+            }
+            _ => {}
+        }
+        prev_hash = hash;
+        count += 1;
+    }
+    Ok(count)
+}
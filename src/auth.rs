@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rocket::http::{Cookie, Cookies};
+use rocket::request::Request;
+use subtle::ConstantTimeEq;
+
+use error::EnokeysError;
+use ADMIN_ACCOUNTS_STORAGE;
+use USERNAME_REGEX;
+
+const SESSION_COOKIE: &str = "enokey_session";
+
+lazy_static! {
+    /// Verified against for unknown accounts, so they take as long as known ones.
+    static ref DUMMY_HASH: String = Argon2::default()
+        .hash_password(b"", &SaltString::generate(&mut OsRng))
+        .unwrap()
+        .to_string();
+}
+
+/// The account name stored in the private session cookie, if the account still exists.
+pub fn session_username(request: &Request) -> Option<String> {
+    request
+        .cookies()
+        .get_private(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|username| account_exists(username))
+}
+
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+fn load_accounts() -> Result<Vec<(String, String)>, EnokeysError> {
+    let mut content = String::new();
+    if let Ok(mut file) = File::open(&*ADMIN_ACCOUNTS_STORAGE) {
+        file.read_to_string(&mut content)?;
+    }
+    Ok(content
+        .split('\n')
+        .filter_map(|line| {
+            let mut split = line.splitn(2, ':');
+            match (split.next(), split.next()) {
+                (Some(username), Some(hash)) => Some((username.to_string(), hash.to_string())),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+fn save_accounts(accounts: &[(String, String)]) -> Result<(), EnokeysError> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&*ADMIN_ACCOUNTS_STORAGE)?;
+    for (username, hash) in accounts {
+        writeln!(file, "{}:{}", username, hash)?;
+    }
+    Ok(())
+}
+
+fn account_exists(username: &str) -> bool {
+    load_accounts()
+        .map(|accounts| accounts.iter().any(|(u, _)| u == username))
+        .unwrap_or(false)
+}
+
+/// Creates the account or resets its password if it already exists.
+pub fn create_account(username: &str, password: &str) -> Result<(), EnokeysError> {
+    if username.is_empty() || USERNAME_REGEX.is_match(username) {
+        return Err(EnokeysError::InvalidData("invalid username".to_string()));
+    }
+    if password.is_empty() {
+        return Err(EnokeysError::InvalidData("empty password".to_string()));
+    }
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))?
+        .to_string();
+    let mut accounts = load_accounts()?;
+    accounts.retain(|(u, _)| u != username);
+    accounts.push((username.to_string(), hash));
+    save_accounts(&accounts)
+}
+
+pub fn delete_account(username: &str) -> Result<(), EnokeysError> {
+    let mut accounts = load_accounts()?;
+    let count = accounts.len();
+    accounts.retain(|(u, _)| u != username);
+    if accounts.len() == count {
+        return Err(EnokeysError::InvalidData(format!(
+            "no such account: {}",
+            username
+        )));
+    }
+    save_accounts(&accounts)
+}
+
+pub fn login(username: &str, password: &str, cookies: &mut Cookies) -> Result<(), EnokeysError> {
+    let accounts = load_accounts()?;
+    let (hash, exists) = match accounts.iter().find(|(u, _)| u == username) {
+        Some((_, hash)) => (hash.as_str(), true),
+        None => (DUMMY_HASH.as_str(), false),
+    };
+    let valid = Argon2::default()
+        .verify_password(password.as_bytes(), &PasswordHash::new(hash)?)
+        .is_ok()
+        && exists;
+    if !valid {
+        return Err(EnokeysError::InvalidCredentials);
+    }
+    cookies.add_private(Cookie::new(SESSION_COOKIE, username.to_string()));
+    Ok(())
+}
+
+pub fn logout(cookies: &mut Cookies) {
+    cookies.remove_private(Cookie::named(SESSION_COOKIE));
+}
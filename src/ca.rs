@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use argon2::password_hash::rand_core::OsRng;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use ssh2::{MethodType, Session};
+use ssh_key::certificate::{Builder, CertType};
+use ssh_key::{Algorithm, HashAlg, LineEnding, PrivateKey, PublicKey};
+
+use audit;
+use authorized_keys_file;
+use deploy::Kind;
+use error::EnokeysError;
+use roles;
+use Context;
+use Destination;
+use CA_STORAGE;
+use CERTIFICATES_STORAGE;
+
+pub const DEFAULT_VALIDITY_HOURS: u64 = 8;
+/// Where `deploy-ca` puts the CA keys for `TrustedUserCAKeys`.
+pub const TRUSTED_KEYS_PATH: &str = "/etc/ssh/enokey_ca.pub";
+/// The CA signing the host keys of all destinations.
+const HOST_CA: &str = "host";
+const HOST_VALIDITY_DAYS: i64 = 365;
+const HOST_KEY_PREFERENCE: &str = "ssh-ed25519,ecdsa-sha2-nistp256,rsa-sha2-512,ssh-rsa";
+const CONNECT_TIMEOUT_MS: u32 = 10_000;
+/// Tolerated clock skew between ENOKEY and the hosts.
+const BACKDATE_MINUTES: i64 = 5;
+/// What `ssh-keygen -s` permits by default.
+const USER_EXTENSIONS: &[&str] = &[
+    "permit-X11-forwarding",
+    "permit-agent-forwarding",
+    "permit-port-forwarding",
+    "permit-pty",
+    "permit-user-rc",
+];
+
+lazy_static! {
+    static ref CA_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// A certificate signed by a group's CA, appended to the certificate log.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct Issued {
+    pub serial: u64,
+    pub group: String,
+    pub key_id: String,
+    pub principals: Vec<String>,
+    /// The SHA256 fingerprint of the certified key.
+    pub fingerprint: String,
+    pub valid_after: DateTime<Utc>,
+    pub valid_before: DateTime<Utc>,
+    pub issued_by: String,
+    /// A host certificate, `key_id` and `principals` name the destination.
+    #[serde(default)]
+    pub host: bool,
+}
+
+fn ca_path(group: &str) -> PathBuf {
+    CA_STORAGE.join(group)
+}
+
+/// The CA key of the group or the host CA, it is created on first use.
+fn ca_key(group: &str) -> Result<PrivateKey, EnokeysError> {
+    let path = ca_path(group);
+    if path.exists() {
+        return Ok(PrivateKey::read_openssh_file(&path)?);
+    }
+    fs::create_dir_all(&*CA_STORAGE)?;
+    let mut key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)?;
+    key.set_comment(format!("enokey-{}-ca", group));
+    key.write_openssh_file(&path, LineEnding::LF)?;
+    info!("Created the CA key of {}", group);
+    Ok(key)
+}
+
+/// The CA keys the hosts of the group trust as `TrustedUserCAKeys`. Admin keys are
+/// accepted on user hosts as well, so user hosts also trust the admin CA.
+pub fn trusted_keys(admin: bool) -> Result<String, EnokeysError> {
+    let _lock = CA_LOCK.lock().unwrap();
+    let groups = if admin {
+        &roles::GROUPS[..1]
+    } else {
+        &roles::GROUPS[..]
+    };
+    let mut keys = String::new();
+    for group in groups {
+        keys.push_str(&ca_key(group)?.public_key().to_openssh()?);
+        keys.push('\n');
+    }
+    Ok(keys)
+}
+
+pub fn audit_record(who: &str, client: &audit::Client, issued: &Issued) -> audit::Record {
+    let mut record = audit::Record::new(
+        who,
+        client,
+        if issued.host {
+            "host-certificate"
+        } else {
+            "certificate"
+        },
+        Some(&issued.group),
+        &format!(
+            "serial {} for {} until {}",
+            issued.serial,
+            issued.principals.join(","),
+            issued.valid_before.to_rfc3339()
+        ),
+    );
+    record.fingerprints = vec![issued.fingerprint.clone()];
+    record
+}
+
+/// Writes the trusted CA keys of the group next to its authorized_keys for deploying.
+pub fn write_trusted_keys(admin: bool) -> Result<PathBuf, EnokeysError> {
+    let path =
+        authorized_keys_file(admin).with_file_name(format!("{}_ca.pub", roles::group_name(admin)));
+    fs::write(&path, trusted_keys(admin)?)?;
+    Ok(path)
+}
+
+fn next_serial() -> Result<u64, EnokeysError> {
+    match File::open(&*CERTIFICATES_STORAGE) {
+        Ok(file) => Ok(BufReader::new(file).lines().count() as u64 + 1),
+        Err(_) => Ok(1),
+    }
+}
+
+fn record(issued: &Issued) -> Result<(), EnokeysError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&*CERTIFICATES_STORAGE)?;
+    writeln!(file, "{}", serde_json::to_string(issued)?)?;
+    Ok(())
+}
+
+/// The issued certificates of the groups, oldest first.
+pub fn issued(groups: &[&str]) -> Result<Vec<Issued>, EnokeysError> {
+    let _lock = CA_LOCK.lock().unwrap();
+    let file = match File::open(&*CERTIFICATES_STORAGE) {
+        Ok(file) => file,
+        Err(_) => return Ok(vec![]),
+    };
+    let mut certificates = vec![];
+    for line in BufReader::new(file).lines() {
+        let issued: Issued = serde_json::from_str(&line?)?;
+        if groups.contains(&issued.group.as_str()) {
+            certificates.push(issued);
+        }
+    }
+    Ok(certificates)
+}
+
+/// Signs a user certificate for `key` with the CA of `group`.
+///
+/// The principals are `group` and, like admin keys are deployed to user hosts, the
+/// groups below it, as far as they are in `groups` and their generated authorized_keys
+/// contain the key. `group` has to be one of them. The certificate is valid for `hours`,
+/// at most for the group's configured validity.
+pub fn sign_user(
+    config: &Context,
+    who: &str,
+    groups: &[&str],
+    group: &str,
+    key: &str,
+    hours: Option<u64>,
+) -> Result<(String, Issued), EnokeysError> {
+    let key = PublicKey::from_openssh(key.trim())?;
+    let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+    let mut principals = vec![];
+    for candidate in roles::GROUPS
+        .iter()
+        .skip_while(|g| **g != group)
+        .filter(|g| groups.contains(g))
+    {
+        let keys = fs::read_to_string(authorized_keys_file(*candidate == roles::GROUPS[0]))
+            .unwrap_or_default();
+        if audit::fingerprints(&keys).contains(&fingerprint) {
+            principals.push(candidate.to_string());
+        }
+    }
+    if !principals.iter().any(|p| p == group) {
+        return Err(EnokeysError::InvalidData(format!(
+            "the key is not approved in {}",
+            group
+        )));
+    }
+
+    let limit = config
+        .cert_validity
+        .get(group)
+        .cloned()
+        .unwrap_or(DEFAULT_VALIDITY_HOURS);
+    let hours = hours.unwrap_or(limit).min(limit);
+    if hours == 0 {
+        return Err(EnokeysError::InvalidData("invalid validity".to_string()));
+    }
+    let _lock = CA_LOCK.lock().unwrap();
+    issue(
+        group,
+        who,
+        who,
+        &key,
+        principals,
+        Duration::hours(hours as i64),
+        false,
+    )
+}
+
+/// Signs `key` with the CA of `group`, or the host CA, from now on for `validity`, and
+/// records the certificate.
+fn issue(
+    group: &str,
+    who: &str,
+    subject: &str,
+    key: &PublicKey,
+    principals: Vec<String>,
+    validity: Duration,
+    host: bool,
+) -> Result<(String, Issued), EnokeysError> {
+    // certificates only carry whole seconds
+    let now = Utc.timestamp_opt(Utc::now().timestamp(), 0).unwrap();
+    let valid_after = now - Duration::minutes(BACKDATE_MINUTES);
+    let valid_before = now + validity;
+    let ca = ca_key(if host { HOST_CA } else { group })?;
+    let serial = next_serial()?;
+    let key_id = format!("{}:{}", subject, serial);
+    let mut builder = Builder::new_with_random_nonce(
+        &mut OsRng,
+        key.key_data().clone(),
+        valid_after.timestamp() as u64,
+        valid_before.timestamp() as u64,
+    )?;
+    builder
+        .serial(serial)?
+        .key_id(key_id.clone())?
+        .cert_type(if host { CertType::Host } else { CertType::User })?
+        .comment(key.comment())?;
+    for principal in &principals {
+        builder.valid_principal(principal.clone())?;
+    }
+    if !host {
+        for extension in USER_EXTENSIONS {
+            builder.extension(*extension, "")?;
+        }
+    }
+    let certificate = builder.sign(&ca)?.to_openssh()?;
+
+    let issued = Issued {
+        serial,
+        group: group.to_string(),
+        key_id,
+        principals,
+        fingerprint: key.fingerprint(HashAlg::Sha256).to_string(),
+        valid_after,
+        valid_before,
+        issued_by: who.to_string(),
+        host,
+    };
+    record(&issued)?;
+    Ok((certificate, issued))
+}
+
+/// Connects to the destination and returns the host key it presents, which is only
+/// trusted once it matches the expected fingerprint.
+fn collect_host_key(destination: &Destination) -> Result<PublicKey, EnokeysError> {
+    let stream = TcpStream::connect((destination.address.as_str(), destination.port))?;
+    let mut session = Session::new()?;
+    session.set_timeout(CONNECT_TIMEOUT_MS);
+    session.method_pref(MethodType::HostKey, HOST_KEY_PREFERENCE)?;
+    session.set_tcp_stream(stream);
+    session.handshake()?;
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| EnokeysError::InvalidData("no host key".to_string()))?;
+    Ok(PublicKey::from_bytes(key)?)
+}
+
+/// Where sshd expects the certificate of the host key, next to the key itself.
+fn host_certificate_path(certificate: &str) -> String {
+    let algorithm = if certificate.starts_with("ssh-ed25519") {
+        "ed25519"
+    } else if certificate.starts_with("ecdsa") {
+        "ecdsa"
+    } else {
+        "rsa"
+    };
+    format!("/etc/ssh/ssh_host_{}_key-cert.pub", algorithm)
+}
+
+/// Signs a host certificate for the address of the destination with the host CA, if
+/// the collected host key has the expected fingerprint.
+fn sign_host(
+    who: &str,
+    group: &str,
+    destination: &Destination,
+    key: &PublicKey,
+    expected: Option<&String>,
+) -> Result<(String, Issued), EnokeysError> {
+    let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+    match expected {
+        Some(expected) if *expected == fingerprint => {}
+        Some(expected) => {
+            warn!(
+                "Host key {} of {} does not match the expected {}",
+                &fingerprint, &destination.destination_name, expected
+            );
+            return Err(EnokeysError::InvalidData(format!(
+                "host key {} does not match the expected {}",
+                fingerprint, expected
+            )));
+        }
+        None => {
+            return Err(EnokeysError::InvalidData(format!(
+                "host key {} not confirmed, pass its fingerprint to sign it",
+                fingerprint
+            )))
+        }
+    }
+    let _lock = CA_LOCK.lock().unwrap();
+    issue(
+        group,
+        who,
+        &destination.address,
+        key,
+        vec![destination.address.clone()],
+        Duration::days(HOST_VALIDITY_DAYS),
+        true,
+    )
+}
+
+/// The outcome of signing the host key of one destination.
+#[derive(Serialize, JsonSchema)]
+pub struct HostCertificate {
+    pub destination: String,
+    /// The certificate in OpenSSH format, to be installed at `path` and configured
+    /// as sshd's `HostCertificate`.
+    pub certificate: Option<String>,
+    pub path: Option<String>,
+    /// The fingerprint of the collected host key, also for keys that were not signed.
+    pub fingerprint: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Signs the host keys of the group's SSH destinations whose fingerprints match the
+/// expected ones by destination name, each certificate is audited.
+pub fn sign_hosts(
+    who: &str,
+    client: &audit::Client,
+    group: &str,
+    destinations: &[Destination],
+    fingerprints: &HashMap<String, String>,
+) -> Vec<HostCertificate> {
+    destinations
+        .iter()
+        .filter(|destination| destination.kind == Kind::Ssh)
+        .map(|destination| {
+            let key = collect_host_key(destination);
+            let fingerprint = key
+                .as_ref()
+                .ok()
+                .map(|key| key.fingerprint(HashAlg::Sha256).to_string());
+            let expected = fingerprints.get(&destination.destination_name);
+            match key.and_then(|key| sign_host(who, group, destination, &key, expected)) {
+                Ok((certificate, issued)) => {
+                    audit::append(&audit_record(who, client, &issued));
+                    HostCertificate {
+                        destination: destination.destination_name.clone(),
+                        path: Some(host_certificate_path(&certificate)),
+                        certificate: Some(certificate),
+                        fingerprint,
+                        error: None,
+                    }
+                }
+                Err(e) => HostCertificate {
+                    destination: destination.destination_name.clone(),
+                    certificate: None,
+                    path: None,
+                    fingerprint,
+                    error: Some(format!("{:?}", e)),
+                },
+            }
+        })
+        .collect()
+}
+
+/// The `known_hosts` patterns naming the SSH destinations, with the port unless it is 22.
+pub fn host_patterns(destinations: &[&Destination]) -> Vec<String> {
+    let mut patterns: Vec<String> = vec![];
+    for destination in destinations.iter().filter(|d| d.kind == Kind::Ssh) {
+        let pattern = if destination.port == 22 {
+            destination.address.clone()
+        } else {
+            format!("[{}]:{}", &destination.address, destination.port)
+        };
+        if !patterns.contains(&pattern) {
+            patterns.push(pattern);
+        }
+    }
+    patterns
+}
+
+/// The `@cert-authority` line for `known_hosts` that trusts the host CA for the patterns.
+pub fn known_hosts(patterns: &[String]) -> Result<String, EnokeysError> {
+    if patterns.is_empty() {
+        return Err(EnokeysError::InvalidData("no destinations".to_string()));
+    }
+    let _lock = CA_LOCK.lock().unwrap();
+    Ok(format!(
+        "@cert-authority {} {}\n",
+        patterns.join(","),
+        ca_key(HOST_CA)?.public_key().to_openssh()?
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMfk4uDkyqCJNtsxbwgw8FVC9+7sKytFomaO+9GWtWI0";
+
+    fn destination() -> Destination {
+        Destination {
+            address: "10.0.0.1".to_string(),
+            userauth_agent: "root".to_string(),
+            destination_name: "root@10.0.0.1:22".to_string(),
+            port: 22,
+            kind: Kind::Ssh,
+        }
+    }
+
+    #[test]
+    fn host_key_needs_the_expected_fingerprint() {
+        let key = PublicKey::from_openssh(HOST_KEY).unwrap();
+        let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+
+        let unconfirmed = sign_host("admin", "user", &destination(), &key, None);
+        match unconfirmed {
+            Err(EnokeysError::InvalidData(message)) => assert!(message.contains(&fingerprint)),
+            _ => panic!("signed an unconfirmed host key"),
+        }
+
+        let other = "SHA256:yBcoGhDlykqUs9iFXZ3Yfa5D1RAv5diOloOJozCEeKs".to_string();
+        let mismatch = sign_host("admin", "user", &destination(), &key, Some(&other));
+        match mismatch {
+            Err(EnokeysError::InvalidData(message)) => assert!(message.contains("does not match")),
+            _ => panic!("signed a host key with another fingerprint"),
+        }
+    }
+}
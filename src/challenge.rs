@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use ssh_key::{PublicKey, SshSig};
+
+use error::EnokeysError;
+
+pub const NAMESPACE: &str = "enokey";
+const CHALLENGE_VALIDITY: Duration = Duration::from_secs(600);
+
+lazy_static! {
+    static ref CHALLENGES: Mutex<HashMap<String, Challenge>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Debug)]
+pub enum Submission {
+    Provider {
+        provider: String,
+        user_name: String,
+        name: String,
+        admin: bool,
+        pending: bool,
+        /// The invitation the submission was made with, used up once it is verified.
+        invitation: Option<String>,
+    },
+    Raw {
+        pub_key: String,
+        name: String,
+        admin: bool,
+        pending: bool,
+        invitation: Option<String>,
+    },
+}
+
+impl Submission {
+    pub fn invitation(&self) -> Option<&str> {
+        match self {
+            Submission::Provider { invitation, .. } | Submission::Raw { invitation, .. } => {
+                invitation.as_deref()
+            }
+        }
+    }
+}
+
+struct Challenge {
+    submission: Submission,
+    keys: Vec<String>,
+    issued: Instant,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ChallengeContext {
+    pub nonce: String,
+    pub description: String,
+    pub namespace: &'static str,
+}
+
+pub fn generate_nonce() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// Remembers a submission until the submitter proves possession of one of `keys`.
+pub fn issue(submission: Submission, keys: Vec<String>) -> String {
+    let nonce = generate_nonce();
+    let mut challenges = CHALLENGES.lock().unwrap();
+    challenges.retain(|_, c| c.issued.elapsed() < CHALLENGE_VALIDITY);
+    challenges.insert(
+        nonce.clone(),
+        Challenge {
+            submission,
+            keys,
+            issued: Instant::now(),
+        },
+    );
+    nonce
+}
+
+/// Consumes the challenge and returns its submission if `signature` is a valid
+/// `ssh-keygen -Y sign -n enokey` signature of the nonce by one of the challenge's keys.
+pub fn redeem(nonce: &str, signature: &str) -> Result<Submission, EnokeysError> {
+    let challenge = match CHALLENGES.lock().unwrap().remove(nonce) {
+        Some(ref c) if c.issued.elapsed() >= CHALLENGE_VALIDITY => {
+            return Err(EnokeysError::InvalidData("challenge expired".to_string()))
+        }
+        Some(c) => c,
+        None => return Err(EnokeysError::InvalidData("unknown challenge".to_string())),
+    };
+    let signature = SshSig::from_pem(signature.trim())?;
+    // `echo` without `-n` signs the nonce including the trailing newline
+    let messages = [nonce.to_string(), format!("{}\n", nonce)];
+    for key in &challenge.keys {
+        if let Ok(key) = PublicKey::from_openssh(key) {
+            if messages
+                .iter()
+                .any(|m| key.verify(NAMESPACE, m.as_bytes(), &signature).is_ok())
+            {
+                return Ok(challenge.submission);
+            }
+        }
+    }
+    Err(EnokeysError::InvalidSignature)
+}
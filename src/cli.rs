@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use ssh_key::PublicKey;
+
+use audit;
+use authorized_keys_file;
+use ca;
+use deploy;
+use deploy_group;
+use error::EnokeysError;
+use hosts;
+use ldap;
+use roles;
+use scraper;
+use storage;
+use Context;
+use CONFIG;
+
+pub const USAGE: &str = "Commands:
+    serve                              Run the web server (default)
+    submit GROUP PROVIDER USER [NAME]  Add a provider account, USER is `PATH[:LEVEL]` for
+                                       <instance>-group and the key for raw
+    list [GROUP]                       List the entries and pending submissions
+    revoke GROUP PATTERN               Remove the entries matching PATTERN, e.g. github:alice,
+                                       a key's base64 blob or bob@raw
+    generate [DIR] [--cache-only]      Write the authorized_keys files, into DIR instead of
+                                       ./keyfiles, without asking providers with --cache-only
+    deploy GROUP [--dry-run]           Deploy the group's authorized_keys to its destinations
+    import GROUP FILE                  Add the keys of an authorized_keys file as raw keys
+    sign-cert GROUP PUBKEY [HOURS]     Sign a user certificate for an approved key of the group
+    certificates [GROUP]               List the issued certificates
+    deploy-ca GROUP [PATH]             Copy the CA keys the group's hosts trust to PATH on its
+                                       destinations (/etc/ssh/enokey_ca.pub)
+    sign-hosts GROUP [DEST=FINGERPRINT...]
+                                       Collect the host keys of the group's destinations and sign
+                                       host certificates for those with the given fingerprints
+    hosts                              List the hosts running the agent and their last check-in
+    agent URL PUBKEY HOST:GROUP [FILE] Install the host's authorized_keys from the server at URL
+                                       into FILE (~/.ssh/authorized_keys), verified with the deploy
+                                       key PUBKEY, every --interval seconds, reads the token from
+                                       ENOKEY_HOST_TOKEN or --host-token-file
+    authorized-keys-command URL PUBKEY USER HOST:GROUP
+                                       Print USER's keys for sshd's AuthorizedKeysCommand, from
+                                       --cache-dir if the server is unreachable";
+
+/// The command line flags that apply to commands.
+pub struct Flags {
+    pub dry_run: bool,
+    pub cache_only: bool,
+    pub interval: u64,
+    pub token_file: Option<String>,
+    pub cache_dir: Option<String>,
+}
+
+/// Who CLI actions are recorded as in the audit log.
+fn who() -> String {
+    match env::var("USER") {
+        Ok(ref user) if !user.is_empty() => format!("cli:{}", user),
+        _ => "cli".to_string(),
+    }
+}
+
+fn record(action: &str, group: &str, details: &str) -> audit::Record {
+    audit::Record::new(&who(), &audit::Client(None), action, Some(group), details)
+}
+
+/// Returns whether `group` is the admin group.
+fn parse_group(group: &str) -> Result<bool, EnokeysError> {
+    if !roles::GROUPS.contains(&group) {
+        return Err(EnokeysError::InvalidData(format!(
+            "unknown group: {}",
+            group
+        )));
+    }
+    Ok(group == roles::GROUPS[0])
+}
+
+fn arg<'a>(args: &'a [String], index: usize, name: &str) -> Result<&'a str, EnokeysError> {
+    args.get(index)
+        .map(|a| a.as_str())
+        .ok_or_else(|| EnokeysError::InvalidData(format!("missing {}\n\n{}", name, USAGE)))
+}
+
+/// Runs the command on the configured storage, for scripts and cron jobs.
+pub fn run(command: &str, args: &[String], flags: &Flags) -> Result<(), EnokeysError> {
+    let config = &*CONFIG.lock().unwrap();
+    if flags.cache_only {
+        scraper::set_cache_only();
+    }
+    match command {
+        "submit" => submit(config, args),
+        "list" => list(args),
+        "revoke" => revoke(args),
+        "generate" => generate(config, args),
+        "deploy" => deploy(config, args, flags.dry_run),
+        "import" => import(args),
+        "hosts" => list_hosts(),
+        "sign-cert" => sign_cert(config, args),
+        "certificates" => certificates(args),
+        "deploy-ca" => deploy_ca(config, args),
+        "sign-hosts" => sign_hosts(config, args),
+        _ => Err(EnokeysError::InvalidData(format!(
+            "unknown command: {}\n\n{}",
+            command, USAGE
+        ))),
+    }
+}
+
+fn submit(config: &Context, args: &[String]) -> Result<(), EnokeysError> {
+    let group = arg(args, 0, "GROUP")?;
+    let admin = parse_group(group)?;
+    let provider = arg(args, 1, "PROVIDER")?;
+    let user = arg(args, 2, "USER")?;
+    let name = args.get(3).map(|n| n.as_str()).unwrap_or(user);
+    let mut record = record(
+        "submit",
+        group,
+        &format!("{} {:?} ({})", provider, user, name),
+    );
+    if provider == "raw" {
+        storage::handle_raw_submission(name, user, admin, false)?;
+        record.details = format!("raw pubkey ({})", name);
+        record.fingerprints = audit::fingerprints(user);
+    } else if provider == ldap::LDAP || provider == ldap::LDAP_GROUP {
+        if config.ldap.is_none() {
+            return Err(EnokeysError::InvalidProviderError(provider.to_string()));
+        }
+        storage::handle_submission(provider, user, name, admin, false)?;
+    } else if let Some(instance) = provider.strip_suffix(scraper::GITLAB_GROUP_SUFFIX) {
+        let mut split = user.splitn(2, ':');
+        let gitlab_group = split.next().unwrap_or_default();
+        let level = match split.next() {
+            Some(level) => level.parse()?,
+            None => scraper::GITLAB_GUEST_ACCESS,
+        };
+        storage::handle_group_submission(instance, gitlab_group, level, name, admin, false)?;
+    } else if scraper::is_provider(provider, &config.gitea_instances) {
+        storage::handle_submission(provider, user, name, admin, false)?;
+    } else {
+        return Err(EnokeysError::InvalidProviderError(provider.to_string()));
+    }
+    audit::append(&record);
+    println!("Added {} {} to {}", provider, user, group);
+    Ok(())
+}
+
+/// The entry on one line, e.g. `github:alice (Alice)`.
+fn describe(entry: &storage::Entry) -> String {
+    if entry.raw {
+        return entry.entry.trim().to_string();
+    }
+    let mut lines = entry.entry.lines();
+    match (lines.next(), lines.next()) {
+        (Some(comment), Some(line)) => {
+            format!("{} ({})", line, comment.trim_start_matches('#').trim())
+        }
+        (Some(line), None) => line.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn list(args: &[String]) -> Result<(), EnokeysError> {
+    let groups = match args.first() {
+        Some(group) => {
+            parse_group(group)?;
+            vec![group.as_str()]
+        }
+        None => roles::GROUPS.to_vec(),
+    };
+    for group in groups {
+        let admin = group == roles::GROUPS[0];
+        for entry in storage::entries(admin)? {
+            println!("{}\t{}", group, describe(&entry));
+        }
+        for entry in storage::pending_entries(admin)? {
+            println!("{}\t[pending] {}", group, describe(&entry));
+        }
+    }
+    Ok(())
+}
+
+fn revoke(args: &[String]) -> Result<(), EnokeysError> {
+    let group = arg(args, 0, "GROUP")?;
+    let admin = parse_group(group)?;
+    let pattern = arg(args, 1, "PATTERN")?;
+    let revoked = storage::revoke_entries(admin, pattern)?;
+    if revoked.is_empty() {
+        return Err(EnokeysError::InvalidData(format!(
+            "no entry in {} matches {:?}",
+            group, pattern
+        )));
+    }
+    for entry in &revoked {
+        let mut record = record("revoke", group, &describe(entry));
+        if entry.raw {
+            record.fingerprints = audit::fingerprints(&entry.entry);
+        }
+        audit::append(&record);
+        println!("Revoked {}", describe(entry));
+    }
+    Ok(())
+}
+
+fn generate(config: &Context, args: &[String]) -> Result<(), EnokeysError> {
+    let output = args.first().map(Path::new);
+    let generated = storage::generate_authorized_key_files_in(config, output)?;
+    let mut failures = 0;
+    for file in &generated {
+        println!("{}: {} keys", file.path.display(), file.keys);
+        for failure in &file.failures {
+            println!("  {}", failure);
+        }
+        failures += file.failures.len();
+    }
+    if failures > 0 {
+        return Err(EnokeysError::InvalidData(format!(
+            "{} entries could not be included",
+            failures
+        )));
+    }
+    Ok(())
+}
+
+fn deploy(config: &Context, args: &[String], dry_run: bool) -> Result<(), EnokeysError> {
+    let group = arg(args, 0, "GROUP")?;
+    let admin = parse_group(group)?;
+    storage::generate_authorized_key_files(config)?;
+    if dry_run {
+        let destinations = if admin {
+            &config.admin_destinations
+        } else {
+            &config.user_destinations
+        };
+        let keys = fs::read_to_string(authorized_keys_file(admin))?;
+        println!(
+            "Would deploy {} keys to {} destinations:",
+            audit::fingerprints(&keys).len(),
+            destinations.len()
+        );
+        for destination in destinations {
+            println!("  {}", &destination.destination_name);
+        }
+        return Ok(());
+    }
+    let hosts = deploy_group(config, admin, &who(), &audit::Client(None));
+    for host in &hosts {
+        println!("{}: {}", &host.destination, &host.message);
+    }
+    let failed = hosts.iter().filter(|h| !h.success).count();
+    if failed > 0 {
+        return Err(EnokeysError::InvalidData(format!(
+            "{} of {} destinations failed",
+            failed,
+            hosts.len()
+        )));
+    }
+    Ok(())
+}
+
+fn import(args: &[String]) -> Result<(), EnokeysError> {
+    let group = arg(args, 0, "GROUP")?;
+    let admin = parse_group(group)?;
+    let content = fs::read_to_string(arg(args, 1, "FILE")?)?;
+    let mut blobs: Vec<String> = vec![];
+    for entry in storage::entries(admin)?.into_iter().filter(|e| e.raw) {
+        blobs.extend(entry.entry.split_whitespace().nth(1).map(|b| b.to_string()));
+    }
+    let (mut imported, mut skipped, mut invalid) = (0, 0, 0);
+    for line in content.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut key = match PublicKey::from_openssh(line) {
+            Ok(key) => key,
+            Err(_) => {
+                println!("Skipping invalid line: {}", line);
+                invalid += 1;
+                continue;
+            }
+        };
+        let name = match key.comment() {
+            "" => "imported".to_string(),
+            comment => comment.to_string(),
+        };
+        key.set_comment("");
+        let key = key.to_openssh()?;
+        let blob = key
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or_default()
+            .to_string();
+        if blobs.contains(&blob) {
+            skipped += 1;
+            continue;
+        }
+        storage::handle_raw_submission(&name, &key, admin, false)?;
+        blobs.push(blob);
+        imported += 1;
+    }
+    let mut record = record(
+        "import",
+        group,
+        &format!("{} keys, {} already present", imported, skipped),
+    );
+    record.fingerprints = audit::fingerprints(&content);
+    audit::append(&record);
+    println!(
+        "Imported {} keys into {}, skipped {} already present and {} invalid lines",
+        imported, group, skipped, invalid
+    );
+    Ok(())
+}
+
+fn list_hosts() -> Result<(), EnokeysError> {
+    for host in hosts::list()? {
+        println!(
+            "{}\t{}\t{}",
+            &host.name,
+            &host.group,
+            host.last_checkin
+                .map_or("never checked in".to_string(), |t| t.to_rfc3339())
+        );
+    }
+    Ok(())
+}
+
+fn sign_cert(config: &Context, args: &[String]) -> Result<(), EnokeysError> {
+    let group = arg(args, 0, "GROUP")?;
+    parse_group(group)?;
+    let key = fs::read_to_string(arg(args, 1, "PUBKEY")?)?;
+    let hours = match args.get(2) {
+        Some(hours) => Some(hours.parse()?),
+        None => None,
+    };
+    storage::generate_authorized_key_files(config)?;
+    let (certificate, issued) = ca::sign_user(config, &who(), &roles::GROUPS, group, &key, hours)?;
+    audit::append(&ca::audit_record(&who(), &audit::Client(None), &issued));
+    println!("{}", certificate);
+    Ok(())
+}
+
+fn certificates(args: &[String]) -> Result<(), EnokeysError> {
+    let groups = match args.first() {
+        Some(group) => {
+            parse_group(group)?;
+            vec![group.as_str()]
+        }
+        None => roles::GROUPS.to_vec(),
+    };
+    for issued in ca::issued(&groups)? {
+        println!(
+            "{}\t{}\t{}\t{}\t{} - {}\t{}",
+            issued.serial,
+            &issued.group,
+            &issued.key_id,
+            issued.principals.join(","),
+            issued.valid_after.to_rfc3339(),
+            issued.valid_before.to_rfc3339(),
+            &issued.fingerprint
+        );
+    }
+    Ok(())
+}
+
+fn deploy_ca(config: &Context, args: &[String]) -> Result<(), EnokeysError> {
+    let group = arg(args, 0, "GROUP")?;
+    let admin = parse_group(group)?;
+    let remote = args.get(1).map_or(ca::TRUSTED_KEYS_PATH, |p| p.as_str());
+    let destinations = if admin {
+        &config.admin_destinations
+    } else {
+        &config.user_destinations
+    };
+    let file = ca::write_trusted_keys(admin)?;
+    let hosts = deploy::deploy_file(group, destinations, &file, remote);
+    let mut record = record("deploy-ca", group, remote);
+    record.fingerprints = audit::fingerprints(&fs::read_to_string(&file)?);
+    record.hosts = hosts.clone();
+    audit::append(&record);
+    for host in &hosts {
+        println!("{}: {}", &host.destination, &host.message);
+    }
+    let failed = hosts.iter().filter(|h| !h.success).count();
+    if failed > 0 {
+        return Err(EnokeysError::InvalidData(format!(
+            "{} of {} destinations failed",
+            failed,
+            hosts.len()
+        )));
+    }
+    Ok(())
+}
+
+fn sign_hosts(config: &Context, args: &[String]) -> Result<(), EnokeysError> {
+    let group = arg(args, 0, "GROUP")?;
+    let admin = parse_group(group)?;
+    let destinations = if admin {
+        &config.admin_destinations
+    } else {
+        &config.user_destinations
+    };
+    let mut fingerprints = HashMap::new();
+    for confirmed in &args[1..] {
+        match confirmed.split_once('=') {
+            Some((destination, fingerprint)) => {
+                fingerprints.insert(destination.to_string(), fingerprint.to_string())
+            }
+            None => {
+                return Err(EnokeysError::InvalidData(format!(
+                    "expected DEST=FINGERPRINT, got {}",
+                    confirmed
+                )))
+            }
+        };
+    }
+    let certificates = ca::sign_hosts(
+        &who(),
+        &audit::Client(None),
+        group,
+        destinations,
+        &fingerprints,
+    );
+    let mut failed = 0;
+    for host in &certificates {
+        match (&host.certificate, &host.path, &host.fingerprint) {
+            (Some(certificate), Some(path), Some(fingerprint)) => {
+                println!(
+                    "# {} ({}), install as {}",
+                    &host.destination, fingerprint, path
+                );
+                println!("{}", certificate);
+            }
+            _ => {
+                println!(
+                    "# {}: {}",
+                    &host.destination,
+                    host.error.as_deref().unwrap_or_default()
+                );
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(EnokeysError::InvalidData(format!(
+            "{} of {} destinations failed",
+            failed,
+            certificates.len()
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+use std::io::Read;
+
+use rocket::data::{self, Data, FromData, Transform, Transformed};
+use rocket::http::{Cookie, Status};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FormItems, FromForm, FromRequest, Request};
+
+use auth;
+use challenge;
+
+const CSRF_COOKIE: &str = "enokey_csrf";
+/// The hidden form field forms pass the token in, e.g. `<input type="hidden" name="csrf" value="{{csrf}}">`.
+const CSRF_FIELD: &str = "csrf";
+
+/// The session's CSRF token to embed into forms, created on first use.
+pub struct CsrfToken(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for CsrfToken {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<CsrfToken, ()> {
+        let mut cookies = request.cookies();
+        if let Some(cookie) = cookies.get_private(CSRF_COOKIE) {
+            return Outcome::Success(CsrfToken(cookie.value().to_string()));
+        }
+        let token = challenge::generate_nonce();
+        cookies.add_private(Cookie::new(CSRF_COOKIE, token.clone()));
+        Outcome::Success(CsrfToken(token))
+    }
+}
+
+/// The token of the session cookie, cached before any guard of the handler holds the cookies.
+struct Expected(Option<String>);
+
+/// Caches the session's token for `CsrfForm`, called from a request fairing.
+pub fn remember(request: &Request) {
+    request.local_cache(|| {
+        Expected(
+            request
+                .cookies()
+                .get_private(CSRF_COOKIE)
+                .map(|cookie| cookie.value().to_string()),
+        )
+    });
+}
+
+/// A form of a state changing request, rejected with 403 if its `csrf` field does not match the
+/// session's token. The remaining fields are parsed strictly into `T`, a parse error is kept so
+/// the handler can report it.
+pub struct CsrfForm<T>(pub Result<T, String>);
+
+impl<'f, T> FromData<'f> for CsrfForm<T>
+where
+    T: for<'a> FromForm<'a>,
+    for<'a> <T as FromForm<'a>>::Error: fmt::Debug,
+{
+    type Error = ();
+    type Owned = String;
+    type Borrowed = str;
+
+    fn transform(request: &Request, data: Data) -> Transform<data::Outcome<String, ()>> {
+        if !request.content_type().is_some_and(|ct| ct.is_form()) {
+            return Transform::Borrowed(Outcome::Forward(data));
+        }
+        let mut form = String::new();
+        let outcome = match data
+            .open()
+            .take(request.limits().get("forms").unwrap_or(32 * 1024))
+            .read_to_string(&mut form)
+        {
+            Ok(_) => Outcome::Success(form),
+            Err(_) => Outcome::Failure((Status::InternalServerError, ())),
+        };
+        Transform::Borrowed(outcome)
+    }
+
+    fn from_data(request: &Request, o: Transformed<'f, Self>) -> data::Outcome<Self, ()> {
+        let form = o.borrowed()?;
+        let mut token = None;
+        let mut fields = vec![];
+        for item in FormItems::from(form) {
+            if item.key.as_str() == CSRF_FIELD {
+                token = item.value.url_decode().ok();
+            } else {
+                fields.push(item.raw.as_str());
+            }
+        }
+        match (&request.local_cache(|| Expected(None)).0, token) {
+            (Some(expected), Some(ref token)) if auth::constant_time_eq(expected, token) => {
+                let fields = fields.join("&");
+                let parsed = T::from_form(&mut FormItems::from(fields.as_str()), true)
+                    .map_err(|e| format!("{:?}", e));
+                Outcome::Success(CsrfForm(parsed))
+            }
+            _ => Outcome::Failure((Status::Forbidden, ())),
+        }
+    }
+}
+
+/// The input of forms that only carry the CSRF token.
+pub struct NoInput;
+
+impl<'f> FromForm<'f> for NoInput {
+    type Error = String;
+
+    fn from_form(items: &mut FormItems<'f>, strict: bool) -> Result<NoInput, String> {
+        match items.next() {
+            Some(item) if strict => Err(format!("unexpected field {}", item.key)),
+            _ => Ok(NoInput),
+        }
+    }
+}
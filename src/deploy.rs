@@ -1,24 +1,555 @@
-use std::path::PathBuf;
-use std::process::Command;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Instant;
 
+use metrics;
 use Destination;
 use EnokeysError;
 
-pub fn deploy(destinations: &[Destination], file: &PathBuf) -> Result<(), EnokeysError> {
-    for destination in destinations {
-        Command::new("scp")
-            .args(&[
-                "-P",
-                &format!("{}", destination.port),
-                "-o",
-                "StrictHostKeyChecking=no",
-                &file.to_str().unwrap().to_string(),
+/// The result of copying the authorized_keys file to one destination.
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+pub struct HostOutcome {
+    pub destination: String,
+    pub success: bool,
+    pub message: String,
+}
+
+pub const AUTHORIZED_KEYS: &str = "~/.ssh/authorized_keys";
+const MANAGED_BY: &str = "enokey";
+/// Writes stdin to `$1/authorized_keys` like `install`, creating the directory `$1`.
+const INSTALL_LOCAL: &str = "set -e; umask 077; mkdir -p \"$1\"; chmod 700 \"$1\"; \
+    cat > \"$1/.authorized_keys.enokey\"; mv -f \"$1/.authorized_keys.enokey\" \"$1/authorized_keys\"";
+
+/// Where a destination's authorized_keys go. Everything but `Ssh` is written to the
+/// local disk, e.g. for a volume, a VM image build or `kubectl apply`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Kind {
+    /// `user@host[:port]`, copied with scp.
+    Ssh,
+    /// `dir:PATH`, written to `PATH/authorized_keys`.
+    Directory(PathBuf),
+    /// `cloud-init:FILE`, a `#cloud-config` with `ssh_authorized_keys`.
+    CloudInit(PathBuf),
+    /// `k8s-secret:NAMESPACE/NAME:FILE`, a Secret manifest.
+    Secret {
+        namespace: String,
+        name: String,
+        path: PathBuf,
+    },
+    /// `k8s-configmap:NAMESPACE/NAME:FILE`, a ConfigMap manifest.
+    ConfigMap {
+        namespace: String,
+        name: String,
+        path: PathBuf,
+    },
+    /// `local:USER`, the user's `~/.ssh/authorized_keys` on this host.
+    Local(String),
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Ssh => "ssh",
+            Kind::Directory(_) => "dir",
+            Kind::CloudInit(_) => "cloud-init",
+            Kind::Secret { .. } => "k8s-secret",
+            Kind::ConfigMap { .. } => "k8s-configmap",
+            Kind::Local(_) => "local",
+        }
+    }
+}
+
+fn invalid(entry: &str) -> EnokeysError {
+    EnokeysError::InvalidData(format!("invalid destination: {}", entry))
+}
+
+/// A lowercase RFC 1123 name as Kubernetes wants it for namespaces and objects.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.')
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric())
+}
+
+/// `NAMESPACE/NAME:FILE` of the Kubernetes kinds.
+fn parse_object(entry: &str, spec: &str) -> Result<(String, String, PathBuf), EnokeysError> {
+    let (object, path) = spec.split_once(':').ok_or_else(|| invalid(entry))?;
+    let (namespace, name) = object.split_once('/').ok_or_else(|| invalid(entry))?;
+    if !valid_name(namespace) || !valid_name(name) || path.is_empty() {
+        return Err(invalid(entry));
+    }
+    Ok((namespace.to_string(), name.to_string(), PathBuf::from(path)))
+}
+
+/// The kind of a destination entry, `None` for an SSH destination.
+pub fn parse_kind(entry: &str) -> Result<Option<Kind>, EnokeysError> {
+    let (prefix, spec) = match entry.split_once(':') {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    let kind = match prefix {
+        "dir" | "cloud-init" if spec.is_empty() => return Err(invalid(entry)),
+        "local" if spec.is_empty() || spec.contains('/') || spec.starts_with('.') => {
+            return Err(invalid(entry))
+        }
+        "local" => Kind::Local(spec.to_string()),
+        "dir" => Kind::Directory(PathBuf::from(spec)),
+        "cloud-init" => Kind::CloudInit(PathBuf::from(spec)),
+        "k8s-secret" => {
+            let (namespace, name, path) = parse_object(entry, spec)?;
+            Kind::Secret {
+                namespace,
+                name,
+                path,
+            }
+        }
+        "k8s-configmap" => {
+            let (namespace, name, path) = parse_object(entry, spec)?;
+            Kind::ConfigMap {
+                namespace,
+                name,
+                path,
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(kind))
+}
+
+/// Replaces `path` by writing a temporary file next to it and renaming it over.
+pub fn install(path: &Path, content: &str) -> Result<(), EnokeysError> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| EnokeysError::InvalidData(format!("invalid path: {}", path.display())))?;
+    let tmp = path.with_file_name(format!(".{}.enokey", file_name.to_string_lossy()));
+    // a leftover of an interrupted install, never follow what is at the name
+    let _ = fs::remove_file(&tmp);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// The uid, gid and home directory of a local user, looked up with getent so users
+/// from LDAP or other NSS sources work too.
+fn local_user(user: &str) -> Result<(u32, u32, PathBuf), EnokeysError> {
+    let output = Command::new("getent").args(["passwd", user]).output()?;
+    let passwd = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = passwd.trim_end().split(':').collect();
+    match (output.status.success(), fields.as_slice()) {
+        (true, [_, _, uid, gid, _, home, ..]) if !home.is_empty() => Ok((
+            uid.parse().map_err(|_| invalid(user))?,
+            gid.parse().map_err(|_| invalid(user))?,
+            PathBuf::from(home),
+        )),
+        _ => Err(EnokeysError::InvalidData(format!(
+            "unknown local user: {}",
+            user
+        ))),
+    }
+}
+
+/// Installs the file as `~/.ssh/authorized_keys` of the local `user`. A shell running as
+/// the user writes it, so links in the user's home cannot redirect the writes elsewhere.
+fn install_local(user: &str, content: &str) -> Result<(), EnokeysError> {
+    let (uid, gid, home) = local_user(user)?;
+    install_as(user, uid, gid, &home.join(".ssh"), content)
+}
+
+/// Writes `dir/authorized_keys` with a shell running as `uid` and `gid`.
+fn install_as(
+    user: &str,
+    uid: u32,
+    gid: u32,
+    dir: &Path,
+    content: &str,
+) -> Result<(), EnokeysError> {
+    let mut child = Command::new("sh")
+        .args(["-c", INSTALL_LOCAL, "sh"])
+        .arg(dir)
+        .uid(uid)
+        .gid(gid)
+        .env_clear()
+        .env("PATH", "/usr/bin:/bin")
+        .current_dir("/")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // a partial file must not be installed, the shell is killed before it sees the end
+    if let Err(e) = stdin.write_all(content.as_bytes()) {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(e.into());
+    }
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(EnokeysError::InvalidData(format!(
+            "installing the keys of {} failed: {}",
+            user,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+fn keys(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+}
+
+// JSON strings are valid double-quoted YAML scalars
+fn quote(value: &str) -> String {
+    serde_json::to_string(value).unwrap()
+}
+
+fn cloud_config(content: &str) -> String {
+    let mut yaml = "#cloud-config\nssh_authorized_keys:".to_string();
+    let mut empty = true;
+    for key in keys(content) {
+        yaml.push_str(&format!("\n  - {}", quote(key)));
+        empty = false;
+    }
+    if empty {
+        yaml.push_str(" []");
+    }
+    yaml.push('\n');
+    yaml
+}
+
+fn manifest(kind: &str, namespace: &str, name: &str, data: &str) -> String {
+    format!(
+        "apiVersion: v1\n\
+         kind: {}\n\
+         metadata:\n  \
+           name: {}\n  \
+           namespace: {}\n  \
+           labels:\n    \
+             app.kubernetes.io/managed-by: {}\n\
+         {}",
+        kind, name, namespace, MANAGED_BY, data
+    )
+}
+
+fn scp(destination: &Destination, file: &Path, remote: &str) -> Result<(), EnokeysError> {
+    let status = Command::new("scp")
+        .arg("-P")
+        .arg(destination.port.to_string())
+        .args(["-o", "StrictHostKeyChecking=no"])
+        .arg(file)
+        .arg(format!(
+            "{}@{}:{}",
+            &destination.userauth_agent, &destination.address, remote
+        ))
+        .status()?;
+    if !status.success() {
+        return Err(EnokeysError::InvalidData(format!("scp failed: {}", status)));
+    }
+    Ok(())
+}
+
+fn deploy_to(destination: &Destination, file: &Path) -> Result<(), EnokeysError> {
+    let content = || fs::read_to_string(file);
+    match &destination.kind {
+        Kind::Ssh => scp(destination, file, AUTHORIZED_KEYS),
+        Kind::Directory(dir) => {
+            fs::create_dir_all(dir)?;
+            install(&dir.join("authorized_keys"), &content()?)
+        }
+        Kind::CloudInit(path) => install(path, &cloud_config(&content()?)),
+        Kind::Secret {
+            namespace,
+            name,
+            path,
+        } => install(
+            path,
+            &manifest(
+                "Secret",
+                namespace,
+                name,
                 &format!(
-                    "{}@{}:~/.ssh/authorized_keys",
-                    &destination.userauth_agent, &destination.address
+                    "type: Opaque\ndata:\n  authorized_keys: {}\n",
+                    base64::encode(content()?)
                 ),
-            ])
-            .status()?;
+            ),
+        ),
+        Kind::ConfigMap {
+            namespace,
+            name,
+            path,
+        } => install(
+            path,
+            &manifest(
+                "ConfigMap",
+                namespace,
+                name,
+                &format!("data:\n  authorized_keys: {}\n", quote(&content()?)),
+            ),
+        ),
+        Kind::Local(user) => install_local(user, &content()?),
+    }
+}
+
+/// Deploys to the destinations, given with their position in the group's list. The
+/// metrics are labelled by group and position, the names contain users and addresses.
+fn outcomes<'a, I, F>(group: &str, destinations: I, deploy_to: F) -> Vec<HostOutcome>
+where
+    I: Iterator<Item = (usize, &'a Destination)>,
+    F: Fn(&Destination) -> Result<(), EnokeysError>,
+{
+    destinations
+        .map(|(index, destination)| {
+            let start = Instant::now();
+            let result = deploy_to(destination);
+            metrics::deploy(
+                &format!("{}-{}", group, index),
+                start.elapsed(),
+                result.is_ok(),
+            );
+            HostOutcome {
+                destination: destination.destination_name.clone(),
+                success: result.is_ok(),
+                message: match result {
+                    Ok(_) => "deployed".to_string(),
+                    Err(e) => format!("{:?}", e),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Deploys the authorized_keys `file` to each of the group's destinations according to its kind.
+pub fn deploy(group: &str, destinations: &[Destination], file: &Path) -> Vec<HostOutcome> {
+    outcomes(group, destinations.iter().enumerate(), |destination| {
+        deploy_to(destination, file)
+    })
+}
+
+/// Copies `file` to the `remote` path on each SSH destination, the others are left out.
+pub fn deploy_file(
+    group: &str,
+    destinations: &[Destination],
+    file: &Path,
+    remote: &str,
+) -> Vec<HostOutcome> {
+    let destinations = destinations
+        .iter()
+        .enumerate()
+        .filter(|(_, d)| d.kind == Kind::Ssh);
+    outcomes(group, destinations, |destination| {
+        scp(destination, file, remote)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::process;
+
+    use libc;
+
+    use super::*;
+
+    const KEYS: &str = "# managed by enokey\n\
+        ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMfk4uDkyqCJNtsxbwgw8FVC9+7sKytFomaO+9GWtWI0 alice@github\n\
+        \n\
+        ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE1Ob7QmA8b0XOMW2QOyQZ5Tdx6d6E4jWZb7x2wTaK0F bob@raw\n";
+
+    fn local(name: &str, kind: Kind) -> Destination {
+        Destination {
+            address: String::new(),
+            userauth_agent: String::new(),
+            destination_name: name.to_string(),
+            port: 0,
+            kind,
+        }
+    }
+
+    #[test]
+    fn parse_valid_kinds() {
+        assert_eq!(parse_kind("root@10.0.0.1:22").unwrap(), None);
+        assert_eq!(parse_kind("root@10.0.0.1").unwrap(), None);
+        assert_eq!(
+            parse_kind("dir:/srv/keys").unwrap(),
+            Some(Kind::Directory(PathBuf::from("/srv/keys")))
+        );
+        assert_eq!(
+            parse_kind("cloud-init:/srv/user-data").unwrap(),
+            Some(Kind::CloudInit(PathBuf::from("/srv/user-data")))
+        );
+        assert_eq!(
+            parse_kind("k8s-secret:ctf/ssh-keys:/srv/secret.yaml").unwrap(),
+            Some(Kind::Secret {
+                namespace: "ctf".to_string(),
+                name: "ssh-keys".to_string(),
+                path: PathBuf::from("/srv/secret.yaml"),
+            })
+        );
+        assert_eq!(
+            parse_kind("k8s-configmap:ctf/ssh-keys:cm.yaml").unwrap(),
+            Some(Kind::ConfigMap {
+                namespace: "ctf".to_string(),
+                name: "ssh-keys".to_string(),
+                path: PathBuf::from("cm.yaml"),
+            })
+        );
+        assert_eq!(
+            parse_kind("local:ctf").unwrap(),
+            Some(Kind::Local("ctf".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_invalid_kinds() {
+        for entry in &[
+            "dir:",
+            "cloud-init:",
+            "local:",
+            "local:../x",
+            "local:.x",
+            "k8s-secret:Bad/x:f",
+            "k8s-secret:ctf/x:",
+            "k8s-secret:ctf:f",
+            "k8s-configmap:ctf/-x:f",
+        ] {
+            assert!(parse_kind(entry).is_err(), "{} was accepted", entry);
+        }
+    }
+
+    #[test]
+    fn cloud_config_lists_the_keys() {
+        assert_eq!(
+            cloud_config("# no keys\n\n"),
+            "#cloud-config\nssh_authorized_keys: []\n"
+        );
+        assert_eq!(
+            cloud_config(KEYS),
+            "#cloud-config\nssh_authorized_keys:\n  \
+             - \"ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMfk4uDkyqCJNtsxbwgw8FVC9+7sKytFomaO+9GWtWI0 alice@github\"\n  \
+             - \"ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE1Ob7QmA8b0XOMW2QOyQZ5Tdx6d6E4jWZb7x2wTaK0F bob@raw\"\n"
+        );
+    }
+
+    #[test]
+    fn deploy_to_local_kinds() {
+        let dir = env::temp_dir().join(format!("enokey-deploy-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("user.authorized_keys");
+        fs::write(&file, KEYS).unwrap();
+        let secret = dir.join("secret.yaml");
+        let configmap = dir.join("configmap.yaml");
+        let destinations = vec![
+            local("keys", Kind::Directory(dir.join("keys"))),
+            local("user-data", Kind::CloudInit(dir.join("user-data"))),
+            local(
+                "secret",
+                Kind::Secret {
+                    namespace: "ctf".to_string(),
+                    name: "ssh-keys".to_string(),
+                    path: secret.clone(),
+                },
+            ),
+            local(
+                "configmap",
+                Kind::ConfigMap {
+                    namespace: "ctf".to_string(),
+                    name: "ssh-keys".to_string(),
+                    path: configmap.clone(),
+                },
+            ),
+            local("missing", Kind::Directory(file.join("keys"))),
+        ];
+
+        let outcomes = deploy("user", &destinations, &file);
+        let failed: Vec<&str> = outcomes
+            .iter()
+            .filter(|o| !o.success)
+            .map(|o| o.destination.as_str())
+            .collect();
+        assert_eq!(failed, vec!["missing"]);
+
+        let installed = dir.join("keys").join("authorized_keys");
+        assert_eq!(fs::read_to_string(&installed).unwrap(), KEYS);
+        assert_eq!(
+            fs::metadata(&installed).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("user-data")).unwrap(),
+            cloud_config(KEYS)
+        );
+
+        let secret = fs::read_to_string(&secret).unwrap();
+        assert!(secret.contains("kind: Secret\n"));
+        assert!(secret.contains("  namespace: ctf\n"));
+        let encoded = secret
+            .lines()
+            .find_map(|l| l.strip_prefix("  authorized_keys: "))
+            .unwrap();
+        assert_eq!(base64::decode(encoded).unwrap(), KEYS.as_bytes());
+
+        let configmap = fs::read_to_string(&configmap).unwrap();
+        assert!(configmap.contains("kind: ConfigMap\n"));
+        assert!(configmap.contains(&format!("  authorized_keys: {}\n", quote(KEYS))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deploy_to_local_users() {
+        let dir = env::temp_dir().join(format!("enokey-local-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let ssh = dir.join(".ssh");
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        install_as("me", uid, gid, &ssh, KEYS).unwrap();
+        let installed = ssh.join("authorized_keys");
+        assert_eq!(fs::read_to_string(&installed).unwrap(), KEYS);
+        assert_eq!(
+            fs::metadata(&ssh).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        assert_eq!(
+            fs::metadata(&installed).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // a link planted by the user is replaced, not written through
+        let target = dir.join("target");
+        fs::write(&target, "").unwrap();
+        fs::remove_file(&installed).unwrap();
+        symlink(&target, &installed).unwrap();
+        install_as("me", uid, gid, &ssh, KEYS).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "");
+        assert!(fs::symlink_metadata(&installed).unwrap().is_file());
+
+        let file = dir.join("user.authorized_keys");
+        fs::write(&file, KEYS).unwrap();
+        let destinations = vec![local(
+            "nobody",
+            Kind::Local("enokey-no-such-user".to_string()),
+        )];
+        let outcomes = deploy("user", &destinations, &file);
+        assert!(!outcomes[0].success);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
-    Ok(())
 }
@@ -1,27 +1,81 @@
+use rocket::http::Status;
+
 #[derive(Debug)]
 pub enum EnokeysError {
     IOError(std::io::Error),
     InvalidEnvironmentError,
     InvalidIntegerError,
     ReqwestError(reqwest::Error),
+    JsonError(serde_json::Error),
     Ssh2Error(ssh2::Error),
+    LdapError(ldap3::LdapError),
+    SshKeyError(ssh_key::Error),
+    PasswordHashError(argon2::password_hash::Error),
+    InvalidSignature,
+    InvalidCredentials,
     InvalidData(String),
     InvalidProviderError(String),
     InvalidProviderResponse,
 }
 
+impl EnokeysError {
+    /// The HTTP status the API answers with.
+    pub fn status(&self) -> Status {
+        match self {
+            EnokeysError::InvalidCredentials => Status::Unauthorized,
+            EnokeysError::InvalidSignature => Status::Forbidden,
+            EnokeysError::InvalidData(_)
+            | EnokeysError::InvalidIntegerError
+            | EnokeysError::InvalidProviderError(_)
+            | EnokeysError::SshKeyError(_) => Status::BadRequest,
+            EnokeysError::ReqwestError(_)
+            | EnokeysError::LdapError(_)
+            | EnokeysError::Ssh2Error(_)
+            | EnokeysError::InvalidProviderResponse => Status::BadGateway,
+            EnokeysError::IOError(_)
+            | EnokeysError::InvalidEnvironmentError
+            | EnokeysError::JsonError(_)
+            | EnokeysError::PasswordHashError(_) => Status::InternalServerError,
+        }
+    }
+}
+
 impl From<ssh2::Error> for EnokeysError {
     fn from(error: ssh2::Error) -> Self {
         EnokeysError::Ssh2Error(error)
     }
 }
 
+impl From<ldap3::LdapError> for EnokeysError {
+    fn from(error: ldap3::LdapError) -> Self {
+        EnokeysError::LdapError(error)
+    }
+}
+
+impl From<ssh_key::Error> for EnokeysError {
+    fn from(error: ssh_key::Error) -> Self {
+        EnokeysError::SshKeyError(error)
+    }
+}
+
+impl From<argon2::password_hash::Error> for EnokeysError {
+    fn from(error: argon2::password_hash::Error) -> Self {
+        EnokeysError::PasswordHashError(error)
+    }
+}
+
 impl From<reqwest::Error> for EnokeysError {
     fn from(error: reqwest::Error) -> Self {
         EnokeysError::ReqwestError(error)
     }
 }
 
+impl From<serde_json::Error> for EnokeysError {
+    fn from(error: serde_json::Error) -> Self {
+        EnokeysError::JsonError(error)
+    }
+}
+
 impl From<std::io::Error> for EnokeysError {
     fn from(error: std::io::Error) -> Self {
         EnokeysError::IOError(error)
@@ -0,0 +1,157 @@
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use sha2::{Digest, Sha256};
+
+use auth;
+use challenge;
+use error::EnokeysError;
+use ratelimit::RateLimit;
+use roles;
+use HOSTS_STORAGE;
+
+lazy_static! {
+    static ref HOSTS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// A host that fetches its group's authorized_keys itself; only the token's hash is stored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Host {
+    pub name: String,
+    pub group: String,
+    hash: String,
+    pub created: DateTime<Utc>,
+    /// The local users that get the group's keys through the AuthorizedKeysCommand.
+    #[serde(default = "default_users")]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub last_checkin: Option<DateTime<Utc>>,
+}
+
+fn default_users() -> Vec<String> {
+    vec!["root".to_string()]
+}
+
+impl Host {
+    pub fn admin(&self) -> bool {
+        self.group == roles::GROUPS[0]
+    }
+}
+
+fn hash(token: &str) -> String {
+    base64::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn load() -> Result<Vec<Host>, EnokeysError> {
+    let mut content = String::new();
+    match File::open(&*HOSTS_STORAGE) {
+        Ok(mut file) => file.read_to_string(&mut content)?,
+        Err(_) => return Ok(vec![]),
+    };
+    Ok(serde_json::from_str(&content)?)
+}
+
+fn save(hosts: &[Host]) -> Result<(), EnokeysError> {
+    let mut file = File::create(&*HOSTS_STORAGE)?;
+    file.write_all(serde_json::to_string_pretty(hosts)?.as_bytes())?;
+    Ok(())
+}
+
+/// Creates a token for the host in `group` and returns it, a previous token of the host stops working.
+/// `users` defaults to root.
+pub fn create(name: &str, group: &str, users: &[&str]) -> Result<String, EnokeysError> {
+    if name.is_empty() || !roles::GROUPS.contains(&group) {
+        return Err(EnokeysError::InvalidData(format!(
+            "invalid host or group: {}:{}",
+            name, group
+        )));
+    }
+    let _lock = HOSTS_LOCK.lock().unwrap();
+    let mut hosts = load()?;
+    hosts.retain(|h| h.name != name);
+    let token = challenge::generate_nonce();
+    hosts.push(Host {
+        name: name.to_string(),
+        group: group.to_string(),
+        hash: hash(&token),
+        created: Utc::now(),
+        users: if users.is_empty() {
+            default_users()
+        } else {
+            users.iter().map(|u| u.to_string()).collect()
+        },
+        last_checkin: None,
+    });
+    save(&hosts)?;
+    Ok(token)
+}
+
+/// Removes the host and returns whether it existed.
+pub fn revoke(name: &str) -> Result<bool, EnokeysError> {
+    let _lock = HOSTS_LOCK.lock().unwrap();
+    let mut hosts = load()?;
+    let count = hosts.len();
+    hosts.retain(|h| h.name != name);
+    save(&hosts)?;
+    Ok(hosts.len() < count)
+}
+
+pub fn list() -> Result<Vec<Host>, EnokeysError> {
+    let _lock = HOSTS_LOCK.lock().unwrap();
+    load()
+}
+
+/// The host the token belongs to, its check-in time is updated.
+fn check_in(token: &str) -> Result<Option<Host>, EnokeysError> {
+    let _lock = HOSTS_LOCK.lock().unwrap();
+    let hash = hash(token);
+    let mut hosts = load()?;
+    let host = match hosts
+        .iter_mut()
+        .find(|h| auth::constant_time_eq(&h.hash, &hash))
+    {
+        Some(host) => {
+            host.last_checkin = Some(Utc::now());
+            host.clone()
+        }
+        None => return Ok(None),
+    };
+    save(&hosts)?;
+    Ok(Some(host))
+}
+
+/// A host authenticated with `Authorization: Bearer <token>`.
+pub struct HostAuth(pub Host);
+
+impl<'a, 'r> FromRequest<'a, 'r> for HostAuth {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<HostAuth, ()> {
+        let token = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "));
+        match token.map(check_in) {
+            Some(Ok(Some(host))) => Outcome::Success(HostAuth(host)),
+            Some(Err(e)) => {
+                error!("Could not load hosts: {:?}", e);
+                Outcome::Failure((Status::InternalServerError, ()))
+            }
+            _ => {
+                if token.is_some() {
+                    if let Outcome::Success(limit) = request.guard::<RateLimit>() {
+                        limit.failure();
+                    }
+                }
+                // not Unauthorized, the catcher would redirect agents to the login page
+                Outcome::Failure((Status::Forbidden, ()))
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+
+use auth;
+use challenge;
+use error::EnokeysError;
+use INVITATIONS_STORAGE;
+
+lazy_static! {
+    static ref INVITATIONS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Invitation {
+    pub token: String,
+    pub admin: bool,
+    pub max_uses: u32,
+    pub uses: u32,
+    pub expires: Option<DateTime<Utc>>,
+    pub revoked: bool,
+    pub created_by: String,
+    pub created: DateTime<Utc>,
+    /// Descriptions of the submissions made with this invitation.
+    pub submissions: Vec<String>,
+}
+
+impl Invitation {
+    pub fn is_valid(&self) -> bool {
+        !self.revoked
+            && self.uses < self.max_uses
+            && self.expires.map(|e| e > Utc::now()).unwrap_or(true)
+    }
+}
+
+fn load() -> Result<Vec<Invitation>, EnokeysError> {
+    let mut content = String::new();
+    match File::open(&*INVITATIONS_STORAGE) {
+        Ok(mut file) => file.read_to_string(&mut content)?,
+        Err(_) => return Ok(vec![]),
+    };
+    Ok(serde_json::from_str(&content)?)
+}
+
+fn save(invitations: &[Invitation]) -> Result<(), EnokeysError> {
+    let mut file = File::create(&*INVITATIONS_STORAGE)?;
+    file.write_all(serde_json::to_string_pretty(invitations)?.as_bytes())?;
+    Ok(())
+}
+
+pub fn list() -> Result<Vec<Invitation>, EnokeysError> {
+    let _lock = INVITATIONS_LOCK.lock().unwrap();
+    load()
+}
+
+pub fn create(
+    admin: bool,
+    max_uses: u32,
+    valid_hours: Option<i64>,
+    created_by: &str,
+) -> Result<Invitation, EnokeysError> {
+    if max_uses == 0 {
+        return Err(EnokeysError::InvalidData(
+            "max uses must be positive".to_string(),
+        ));
+    }
+    let _lock = INVITATIONS_LOCK.lock().unwrap();
+    let mut invitations = load()?;
+    let invitation = Invitation {
+        token: challenge::generate_nonce(),
+        admin,
+        max_uses,
+        uses: 0,
+        expires: valid_hours.map(|h| Utc::now() + Duration::hours(h)),
+        revoked: false,
+        created_by: created_by.to_string(),
+        created: Utc::now(),
+        submissions: vec![],
+    };
+    invitations.push(invitation.clone());
+    save(&invitations)?;
+    Ok(invitation)
+}
+
+pub fn revoke(token: &str, admin: bool) -> Result<(), EnokeysError> {
+    let _lock = INVITATIONS_LOCK.lock().unwrap();
+    let mut invitations = load()?;
+    match invitations
+        .iter_mut()
+        .find(|i| i.token == token && i.admin == admin)
+    {
+        Some(invitation) => invitation.revoked = true,
+        None => return Err(EnokeysError::InvalidData("unknown invitation".to_string())),
+    }
+    save(&invitations)
+}
+
+/// Whether the invitation grants the admin group, if it is valid. Nothing is used up.
+pub fn check(token: &str) -> Result<bool, EnokeysError> {
+    let _lock = INVITATIONS_LOCK.lock().unwrap();
+    load()?
+        .iter()
+        .find(|i| auth::constant_time_eq(&i.token, token) && i.is_valid())
+        .map(|i| i.admin)
+        .ok_or(EnokeysError::InvalidCredentials)
+}
+
+/// Uses up one use of the invitation and records the submission.
+/// Returns whether the invitation grants the admin group.
+pub fn redeem(token: &str, submission: &str) -> Result<bool, EnokeysError> {
+    let _lock = INVITATIONS_LOCK.lock().unwrap();
+    let mut invitations = load()?;
+    let admin = match invitations
+        .iter_mut()
+        .find(|i| auth::constant_time_eq(&i.token, token) && i.is_valid())
+    {
+        Some(invitation) => {
+            invitation.uses += 1;
+            invitation
+                .submissions
+                .push(format!("{} {}", Utc::now().to_rfc3339(), submission));
+            invitation.admin
+        }
+        None => return Err(EnokeysError::InvalidCredentials),
+    };
+    save(&invitations)?;
+    Ok(admin)
+}
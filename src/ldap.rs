@@ -0,0 +1,362 @@
+use ldap3::{ldap_escape, LdapConn, LdapError, Scope, SearchEntry};
+
+use error::EnokeysError;
+
+pub const LDAP: &str = "ldap";
+pub const LDAP_GROUP: &str = "ldap-group";
+
+const SSH_PUBLIC_KEY: &str = "sshPublicKey";
+/// The result code of a search whose base does not exist.
+const NO_SUCH_OBJECT: u32 = 32;
+
+#[derive(Clone, Debug)]
+pub struct LdapConfig {
+    pub url: String,
+    pub base_dn: String,
+    pub bind_dn: Option<String>,
+    pub bind_password: String,
+    pub filter: String,
+}
+
+/// The operations the lookups need from a directory connection.
+trait Directory {
+    fn search(
+        &mut self,
+        base: &str,
+        scope: Scope,
+        filter: &str,
+        attrs: Vec<&str>,
+    ) -> Result<Vec<SearchEntry>, EnokeysError>;
+
+    fn unbind(&mut self) -> Result<(), EnokeysError>;
+}
+
+impl Directory for LdapConn {
+    fn search(
+        &mut self,
+        base: &str,
+        scope: Scope,
+        filter: &str,
+        attrs: Vec<&str>,
+    ) -> Result<Vec<SearchEntry>, EnokeysError> {
+        let (entries, _) = LdapConn::search(self, base, scope, filter, attrs)?.success()?;
+        Ok(entries.into_iter().map(SearchEntry::construct).collect())
+    }
+
+    fn unbind(&mut self) -> Result<(), EnokeysError> {
+        Ok(LdapConn::unbind(self)?)
+    }
+}
+
+fn connect(config: &LdapConfig) -> Result<LdapConn, EnokeysError> {
+    let mut conn = LdapConn::new(&config.url)?;
+    if let Some(ref bind_dn) = config.bind_dn {
+        let bound = conn
+            .simple_bind(bind_dn, &config.bind_password)
+            .and_then(|result| result.success());
+        if let Err(e) = bound {
+            let _ = conn.unbind();
+            return Err(e.into());
+        }
+    }
+    Ok(conn)
+}
+
+/// Runs `lookup` and unbinds the connection afterwards, also if the lookup failed.
+fn unbinding<D, T, F>(conn: &mut D, lookup: F) -> Result<T, EnokeysError>
+where
+    D: Directory,
+    F: FnOnce(&mut D) -> Result<T, EnokeysError>,
+{
+    let result = lookup(conn);
+    let unbound = conn.unbind();
+    let value = result?;
+    unbound?;
+    Ok(value)
+}
+
+fn user_filter(config: &LdapConfig, uid: &str) -> String {
+    format!("(&(uid={}){})", ldap_escape(uid), &config.filter)
+}
+
+fn search_keys<D: Directory>(
+    conn: &mut D,
+    base: &str,
+    scope: Scope,
+    filter: &str,
+) -> Result<Vec<String>, EnokeysError> {
+    let entries = conn.search(base, scope, filter, vec![SSH_PUBLIC_KEY, "uid"])?;
+    let mut keys = vec![];
+    for entry in entries {
+        let uid = match entry.attrs.get("uid").and_then(|u| u.first()) {
+            Some(uid) => uid.clone(),
+            None => {
+                warn!("Skipping LDAP entry {} without uid", &entry.dn);
+                continue;
+            }
+        };
+        if let Some(user_keys) = entry.attrs.get(SSH_PUBLIC_KEY) {
+            for key in user_keys {
+                keys.push(format!("{} {}@{}", key.trim(), uid, LDAP));
+            }
+        }
+    }
+    Ok(keys)
+}
+
+fn user_keys<D: Directory>(
+    conn: &mut D,
+    config: &LdapConfig,
+    uid: &str,
+) -> Result<Vec<String>, EnokeysError> {
+    search_keys(
+        conn,
+        &config.base_dn,
+        Scope::Subtree,
+        &user_filter(config, uid),
+    )
+}
+
+fn group_keys<D: Directory>(
+    conn: &mut D,
+    config: &LdapConfig,
+    group: &str,
+) -> Result<Vec<String>, EnokeysError> {
+    let entries = conn.search(
+        &config.base_dn,
+        Scope::Subtree,
+        &format!(
+            "(&(cn={})(|(objectClass=posixGroup)(objectClass=groupOfNames)))",
+            ldap_escape(group)
+        ),
+        vec!["memberUid", "member"],
+    )?;
+    if entries.is_empty() {
+        return Err(EnokeysError::InvalidData(format!(
+            "ldap group {} not found",
+            group
+        )));
+    }
+    let mut keys = vec![];
+    for entry in entries {
+        for uid in entry.attrs.get("memberUid").into_iter().flatten() {
+            keys.extend(user_keys(conn, config, uid)?);
+        }
+        for dn in entry.attrs.get("member").into_iter().flatten() {
+            match search_keys(
+                conn,
+                dn,
+                Scope::Base,
+                &format!("(&(objectClass=*){})", &config.filter),
+            ) {
+                Ok(member_keys) => keys.extend(member_keys),
+                // a member that was deleted must not drop the keys of the others
+                Err(EnokeysError::LdapError(LdapError::LdapResult { ref result }))
+                    if result.rc == NO_SUCH_OBJECT =>
+                {
+                    warn!(
+                        "Skipping LDAP member {} of {} that does not exist",
+                        dn, group
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    keys.sort();
+    keys.dedup();
+    Ok(keys)
+}
+
+pub fn fetch_user(config: &LdapConfig, uid: &str) -> Result<Vec<String>, EnokeysError> {
+    unbinding(&mut connect(config)?, |conn| user_keys(conn, config, uid))
+}
+
+/// Resolves both `posixGroup` (`memberUid`) and `groupOfNames` (`member`) style groups.
+pub fn fetch_group(config: &LdapConfig, group: &str) -> Result<Vec<String>, EnokeysError> {
+    unbinding(&mut connect(config)?, |conn| {
+        group_keys(conn, config, group)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use ldap3::LdapResult;
+
+    use super::*;
+
+    const ALICE_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMfk4uDkyqCJNtsxbwgw8FVC9+7sKytFomaO+9GWtWI0";
+    const BOB_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIE1Ob7QmA8b0XOMW2QOyQZ5Tdx6d6E4jWZb7x2wTaK0F";
+
+    /// Answers searches by base and filter, like a directory that holds exactly these entries.
+    #[derive(Default)]
+    struct MockDirectory {
+        entries: HashMap<(String, String), Vec<SearchEntry>>,
+        unbound: bool,
+    }
+
+    impl MockDirectory {
+        fn add(&mut self, base: &str, filter: &str, dn: &str, attrs: &[(&str, &[&str])]) {
+            self.entries
+                .entry((base.to_string(), filter.to_string()))
+                .or_default()
+                .push(SearchEntry {
+                    dn: dn.to_string(),
+                    attrs: attrs
+                        .iter()
+                        .map(|(name, values)| {
+                            (
+                                name.to_string(),
+                                values.iter().map(|v| v.to_string()).collect(),
+                            )
+                        })
+                        .collect(),
+                    bin_attrs: HashMap::new(),
+                });
+        }
+    }
+
+    impl Directory for MockDirectory {
+        fn search(
+            &mut self,
+            base: &str,
+            _scope: Scope,
+            filter: &str,
+            _attrs: Vec<&str>,
+        ) -> Result<Vec<SearchEntry>, EnokeysError> {
+            if self.unbound {
+                return Err(EnokeysError::InvalidData("unbound".to_string()));
+            }
+            match self.entries.get(&(base.to_string(), filter.to_string())) {
+                Some(entries) => Ok(entries.clone()),
+                None if base == "dc=example,dc=org" => Ok(vec![]),
+                None => Err(EnokeysError::LdapError(LdapError::LdapResult {
+                    result: LdapResult {
+                        rc: NO_SUCH_OBJECT,
+                        matched: String::new(),
+                        text: format!("no such object: {}", base),
+                        refs: vec![],
+                        ctrls: vec![],
+                    },
+                })),
+            }
+        }
+
+        fn unbind(&mut self) -> Result<(), EnokeysError> {
+            self.unbound = true;
+            Ok(())
+        }
+    }
+
+    fn config() -> LdapConfig {
+        LdapConfig {
+            url: "ldap://mock".to_string(),
+            base_dn: "dc=example,dc=org".to_string(),
+            bind_dn: None,
+            bind_password: String::new(),
+            filter: "(objectClass=ldapPublicKey)".to_string(),
+        }
+    }
+
+    fn directory() -> MockDirectory {
+        let base = "dc=example,dc=org";
+        let mut directory = MockDirectory::default();
+        directory.add(
+            base,
+            "(&(uid=alice)(objectClass=ldapPublicKey))",
+            "uid=alice,dc=example,dc=org",
+            &[("uid", &["alice"]), (SSH_PUBLIC_KEY, &[ALICE_KEY])],
+        );
+        directory.add(
+            "uid=bob,dc=example,dc=org",
+            "(&(objectClass=*)(objectClass=ldapPublicKey))",
+            "uid=bob,dc=example,dc=org",
+            &[("uid", &["bob"]), (SSH_PUBLIC_KEY, &[BOB_KEY])],
+        );
+        directory.add(
+            "cn=service,dc=example,dc=org",
+            "(&(objectClass=*)(objectClass=ldapPublicKey))",
+            "cn=service,dc=example,dc=org",
+            &[(SSH_PUBLIC_KEY, &[BOB_KEY])],
+        );
+        directory.add(
+            base,
+            "(&(cn=ctf)(|(objectClass=posixGroup)(objectClass=groupOfNames)))",
+            "cn=ctf,dc=example,dc=org",
+            &[
+                ("memberUid", &["alice", "nobody"]),
+                (
+                    "member",
+                    &["uid=bob,dc=example,dc=org", "cn=service,dc=example,dc=org"],
+                ),
+            ],
+        );
+        directory.add(
+            base,
+            "(&(cn=broken)(|(objectClass=posixGroup)(objectClass=groupOfNames)))",
+            "cn=broken,dc=example,dc=org",
+            &[(
+                "member",
+                &["uid=gone,dc=example,dc=org", "uid=bob,dc=example,dc=org"],
+            )],
+        );
+        directory
+    }
+
+    #[test]
+    fn user_lookup() {
+        let mut directory = directory();
+        let keys = unbinding(&mut directory, |conn| user_keys(conn, &config(), "alice")).unwrap();
+        assert_eq!(keys, vec![format!("{} alice@ldap", ALICE_KEY)]);
+        assert!(directory.unbound);
+
+        let mut directory = self::directory();
+        let keys = unbinding(&mut directory, |conn| user_keys(conn, &config(), "mallory")).unwrap();
+        assert!(keys.is_empty());
+    }
+
+    #[test]
+    fn user_lookup_escapes_the_filter() {
+        let mut directory = directory();
+        let keys = user_keys(&mut directory, &config(), "alice)(uid=*").unwrap();
+        assert!(keys.is_empty());
+    }
+
+    #[test]
+    fn group_lookup() {
+        let mut directory = directory();
+        let keys = unbinding(&mut directory, |conn| group_keys(conn, &config(), "ctf")).unwrap();
+        // the member without a uid is skipped, unknown memberUids have no keys
+        assert_eq!(
+            keys,
+            vec![
+                format!("{} bob@ldap", BOB_KEY),
+                format!("{} alice@ldap", ALICE_KEY),
+            ]
+        );
+        assert!(directory.unbound);
+    }
+
+    #[test]
+    fn unbinds_on_errors() {
+        let mut directory = directory();
+        let result = unbinding(&mut directory, |conn| {
+            group_keys(conn, &config(), "missing")
+        });
+        assert!(result.is_err());
+        assert!(directory.unbound);
+    }
+
+    #[test]
+    fn skips_dangling_members() {
+        // a deleted member is skipped, the other members keep their keys
+        let mut directory = directory();
+        let keys = unbinding(&mut directory, |conn| group_keys(conn, &config(), "broken")).unwrap();
+        assert_eq!(keys, vec![format!("{} bob@ldap", BOB_KEY)]);
+        assert!(directory.unbound);
+    }
+}
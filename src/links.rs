@@ -0,0 +1,70 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use error::EnokeysError;
+use LINK_KEY_STORAGE;
+
+lazy_static! {
+    static ref LINK_KEY: Vec<u8> = load_key().expect("could not load link signing key");
+}
+
+/// Loads the key links are signed with, creating it on first use so that
+/// links stay valid across restarts.
+fn load_key() -> Result<Vec<u8>, EnokeysError> {
+    let mut key = vec![];
+    if let Ok(mut file) = File::open(&*LINK_KEY_STORAGE) {
+        file.read_to_end(&mut key)?;
+        if key.len() == 32 {
+            return Ok(key);
+        }
+    }
+    key = vec![0; 32];
+    rand::thread_rng().fill_bytes(&mut key);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&*LINK_KEY_STORAGE)?;
+    file.write_all(&key)?;
+    Ok(key)
+}
+
+fn mac(file: &str, expires: i64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&LINK_KEY).unwrap();
+    mac.update(format!("{}:{}", file, expires).as_bytes());
+    mac
+}
+
+/// Returns the `expires` and `signature` query parameters for a download link of `file`.
+pub fn sign(file: &str, valid_hours: i64) -> Result<(i64, String), EnokeysError> {
+    if valid_hours <= 0 {
+        return Err(EnokeysError::InvalidData(
+            "validity must be positive".to_string(),
+        ));
+    }
+    let expires = (Utc::now() + Duration::hours(valid_hours)).timestamp();
+    let signature = base64::encode_config(
+        mac(file, expires).finalize().into_bytes(),
+        base64::URL_SAFE_NO_PAD,
+    );
+    Ok((expires, signature))
+}
+
+pub fn verify(file: &str, expires: i64, signature: &str) -> bool {
+    if expires < Utc::now().timestamp() {
+        return false;
+    }
+    match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+        Ok(signature) => mac(file, expires).verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
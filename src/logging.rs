@@ -0,0 +1,114 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use regex::Regex;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+use tracing::span::EnteredSpan;
+use tracing_subscriber::filter::LevelFilter;
+
+use challenge;
+
+lazy_static! {
+    /// PSKs, tokens and passwords from the configuration, never written to the log.
+    static ref SECRETS: Mutex<Vec<String>> = Mutex::new(vec![]);
+    /// Query and form parameters that carry credentials, e.g. in Rocket's request lines.
+    static ref SECRET_PARAMS: Regex =
+        Regex::new(r"\b(authkey|invitation|csrf|signature|token|password|code|state)=[^&\s\x22]+").unwrap();
+}
+
+thread_local! {
+    /// Rocket 0.4 handles a request on a single thread, so the span entered
+    /// in `on_request` stays current until `on_response` drops it.
+    static REQUEST_SPAN: RefCell<Option<EnteredSpan>> = const { RefCell::new(None) };
+}
+
+/// Replaces every occurrence of `secret` in log output with `[redacted]`.
+pub fn redact_secret(secret: &str) {
+    if !secret.is_empty() {
+        SECRETS.lock().unwrap().push(secret.to_string());
+    }
+}
+
+fn redact(output: &str) -> String {
+    let mut output = SECRET_PARAMS
+        .replace_all(output, "$1=[redacted]")
+        .into_owned();
+    for secret in SECRETS.lock().unwrap().iter() {
+        output = output.replace(secret.as_str(), "[redacted]");
+    }
+    output
+}
+
+/// Writes log lines to stdout, or stderr, with all secrets removed.
+struct RedactingWriter {
+    stderr: bool,
+}
+
+impl Write for RedactingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let output = redact(&String::from_utf8_lossy(buf));
+        if self.stderr {
+            io::stderr().write_all(output.as_bytes())?;
+        } else {
+            io::stdout().write_all(output.as_bytes())?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.stderr {
+            io::stderr().flush()
+        } else {
+            io::stdout().flush()
+        }
+    }
+}
+
+/// Installs the global subscriber, which also receives Rocket's `log` records.
+pub fn init(level: LevelFilter, json: bool, stderr: bool) {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_ansi(false)
+        .with_writer(move || RedactingWriter { stderr });
+    if json {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
+
+/// The id of the current request, also sent back in the `X-Request-Id` header.
+struct RequestId(String);
+
+/// Opens a span with a fresh request id around every request.
+pub struct RequestSpans;
+
+impl Fairing for RequestSpans {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request spans",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let id = challenge::generate_nonce()[..12].to_string();
+        let span = info_span!(
+            "request",
+            id = %id,
+            method = %request.method(),
+            path = %request.uri().path()
+        );
+        request.local_cache(|| RequestId(id));
+        REQUEST_SPAN.with(|current| *current.borrow_mut() = Some(span.entered()));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let id = request.local_cache(|| RequestId(String::new()));
+        response.set_raw_header("X-Request-Id", id.0.clone());
+        info!(status = response.status().code, "response");
+        REQUEST_SPAN.with(|current| current.borrow_mut().take());
+    }
+}
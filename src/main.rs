@@ -2,47 +2,91 @@
 
 #[macro_use]
 extern crate rocket;
+extern crate argon2;
 extern crate base64;
+extern crate chrono;
 extern crate getopts;
+extern crate hmac;
+extern crate ldap3;
+extern crate libc;
 extern crate regex;
 extern crate rocket_contrib;
+extern crate rsa;
+#[macro_use]
+extern crate schemars;
 #[macro_use]
 extern crate lazy_static;
 extern crate openssh_keys;
+extern crate rand;
 extern crate reqwest;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
+#[macro_use]
+extern crate serde_derive;
 extern crate ssh2;
+extern crate ssh_key;
+extern crate subtle;
+#[macro_use]
+extern crate tracing;
+extern crate tracing_subscriber;
 
+mod agent;
+mod api;
+mod audit;
+mod auth;
+mod ca;
+mod challenge;
+mod cli;
+mod csrf;
 mod deploy;
 mod error;
+mod hosts;
+mod invitations;
+mod ldap;
+mod links;
+mod logging;
+mod metrics;
+mod oidc;
+mod ratelimit;
+mod roles;
 mod scraper;
 mod storage;
+mod tokens;
 
 use error::EnokeysError;
 
 use std::collections::HashMap;
 use std::env;
+use std::fs;
 use std::io;
 use std::path::PathBuf;
+use std::process;
 use std::sync::Mutex;
 
+use rocket::config::Environment;
 use rocket::fairing::AdHoc;
-use rocket::http::RawStr;
-use rocket::request::{Form, FormError, FromFormValue};
+use rocket::http::uri::Uri;
+use rocket::http::{ContentType, Cookies, RawStr, Status};
+use rocket::request::{Form, FormError, FromFormValue, Request};
 use rocket::response::content;
-use rocket::response::NamedFile;
+use rocket::response::{NamedFile, Redirect};
 use rocket_contrib::serve::StaticFiles;
 use rocket_contrib::templates::Template;
 
 use getopts::Options;
 use regex::Regex;
+use tracing_subscriber::filter::LevelFilter;
 
 fn print_usage(program: &str, opts: &Options) {
-    let brief = format!("Usage: {} [options]", program);
-    println!("{}", opts.usage(&brief));
+    let brief = format!("Usage: {} [options] [COMMAND [ARGS]]", program);
+    println!("{}\n{}", opts.usage(&brief), cli::USAGE);
 }
 
 lazy_static! {
     static ref USERNAME_REGEX: Regex = Regex::new(r"[^A-Za-z0-9\.@!\-_]").unwrap();
+    static ref GROUPNAME_REGEX: Regex = Regex::new(r"[^A-Za-z0-9\.\-_/]").unwrap();
     static ref USER_DESTINATIONS_STORAGE_RAW: PathBuf = PathBuf::from("./data/user.raw");
     static ref USER_DESTINATIONS_STORAGE_PROVIDERS: PathBuf =
         PathBuf::from("./data/user.providers");
@@ -53,11 +97,34 @@ lazy_static! {
         PathBuf::from("./data/admin.providers");
     static ref ADMIN_DESTINATIONS_AUTHORIZED_KEYS: PathBuf =
         PathBuf::from("./keyfiles/admin.authorized_keys");
+    static ref ADMIN_ACCOUNTS_STORAGE: PathBuf = PathBuf::from("./data/admin.accounts");
+    static ref INVITATIONS_STORAGE: PathBuf = PathBuf::from("./data/invitations.json");
+    static ref ROLES_STORAGE: PathBuf = PathBuf::from("./data/roles");
+    static ref LINK_KEY_STORAGE: PathBuf = PathBuf::from("./data/link.key");
+    static ref AUDIT_STORAGE: PathBuf = PathBuf::from("./data/audit.jsonl");
+    static ref API_TOKENS_STORAGE: PathBuf = PathBuf::from("./data/api_tokens.json");
+    static ref HOSTS_STORAGE: PathBuf = PathBuf::from("./data/hosts.json");
+    static ref CA_STORAGE: PathBuf = PathBuf::from("./data/ca");
+    static ref CERTIFICATES_STORAGE: PathBuf = PathBuf::from("./data/certificates.jsonl");
     static ref CONFIG: Mutex<Context> = Mutex::new(Context {
         admin_destinations: vec!(),
         user_destinations: vec!(),
         admin_psk: "default".to_string(),
-        user_psk: "default".to_string()
+        user_psk: "default".to_string(),
+        gitlab_tokens: HashMap::new(),
+        gitea_instances: HashMap::new(),
+        ldap: None,
+        verify_providers: false,
+        verify_raw_keys: false,
+        oidc: None,
+        require_approval: false,
+        limits: ratelimit::Limits {
+            requests: 30,
+            failures: 5
+        },
+        metrics_token: None,
+        known_hosts_pattern: None,
+        cert_validity: HashMap::new(),
     });
 }
 
@@ -67,6 +134,7 @@ pub struct Destination {
     userauth_agent: String,
     destination_name: String,
     port: u16,
+    kind: deploy::Kind,
 }
 
 pub struct Context {
@@ -74,6 +142,36 @@ pub struct Context {
     user_destinations: Vec<Destination>,
     admin_psk: String,
     user_psk: String,
+    gitlab_tokens: HashMap<String, String>,
+    gitea_instances: HashMap<String, String>,
+    ldap: Option<ldap::LdapConfig>,
+    verify_providers: bool,
+    verify_raw_keys: bool,
+    oidc: Option<oidc::OidcConfig>,
+    require_approval: bool,
+    limits: ratelimit::Limits,
+    metrics_token: Option<String>,
+    /// Published in `/ca/known_hosts` instead of the destinations, e.g. `*.ctf.example`.
+    known_hosts_pattern: Option<String>,
+    /// The longest validity of user certificates in hours, by group.
+    cert_validity: HashMap<String, u64>,
+}
+
+/// The group a submission goes to and whether it has to be approved first.
+#[derive(Clone, Copy, Debug)]
+struct Target {
+    admin: bool,
+    pending: bool,
+}
+
+impl Target {
+    fn note(&self) -> &'static str {
+        if self.pending {
+            ", pending approval by a reviewer"
+        } else {
+            ""
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -82,6 +180,12 @@ enum FormOption {
     TubLab,
     GitLab,
     EnoLab,
+    GitLabGroup,
+    Codeberg,
+    SourceHut,
+    Gitea,
+    Ldap,
+    LdapGroup,
     PubKey,
 }
 
@@ -94,6 +198,12 @@ impl<'v> FromFormValue<'v> for FormOption {
             "TubLab" => FormOption::TubLab,
             "EnoLab" => FormOption::EnoLab,
             "GitLab" => FormOption::GitLab,
+            "GitLabGroup" => FormOption::GitLabGroup,
+            "Codeberg" => FormOption::Codeberg,
+            "SourceHut" => FormOption::SourceHut,
+            "Gitea" => FormOption::Gitea,
+            "Ldap" => FormOption::Ldap,
+            "LdapGroup" => FormOption::LdapGroup,
             "PubKey" => FormOption::PubKey,
             _ => return Err(v),
         };
@@ -114,140 +224,1171 @@ struct FormInput {
     gitlab_username: String,
     #[form(field = "enolabuser")]
     enolab_username: String,
+    #[form(field = "gitlabgroup")]
+    gitlab_group: String,
+    #[form(field = "gitlabgroupinstance")]
+    gitlab_group_instance: String,
+    #[form(field = "gitlabgroupaccess")]
+    gitlab_group_access_level: u32,
+    #[form(field = "codeberguser")]
+    codeberg_username: String,
+    #[form(field = "sourcehutuser")]
+    sourcehut_username: String,
+    #[form(field = "giteainstance")]
+    gitea_instance: Option<String>,
+    #[form(field = "giteauser")]
+    gitea_username: Option<String>,
+    #[form(field = "ldapuser")]
+    ldap_username: Option<String>,
+    #[form(field = "ldapgroup")]
+    ldap_group: Option<String>,
     #[form(field = "sshpublic")]
     pub_key: String,
     authkey: String,
 }
 
 #[derive(Debug, FromForm)]
-struct DeployInput {
-    authkey: String,
+struct VerifyInput {
+    challenge: String,
+    signature: String,
+}
+
+impl FormInput {
+    fn provider_user(&self) -> Option<(&str, &str)> {
+        match self.radio {
+            FormOption::GitHub => Some(("github", &self.github_username)),
+            FormOption::TubLab => Some(("tublab", &self.tublab_username)),
+            FormOption::GitLab => Some(("gitlab", &self.gitlab_username)),
+            FormOption::EnoLab => Some(("enolab", &self.enolab_username)),
+            FormOption::Codeberg => Some(("codeberg", &self.codeberg_username)),
+            FormOption::SourceHut => Some(("sourcehut", &self.sourcehut_username)),
+            FormOption::Gitea => match (&self.gitea_instance, &self.gitea_username) {
+                (Some(instance), Some(username)) => Some((instance, username)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> String {
+        let entry = match self.provider_user() {
+            Some((provider, user_name)) => format!("{}:{}", provider, user_name),
+            None => match self.radio {
+                FormOption::GitLabGroup => format!(
+                    "{}-group:{}",
+                    &self.gitlab_group_instance, &self.gitlab_group
+                ),
+                FormOption::Ldap => {
+                    format!("ldap:{}", self.ldap_username.as_ref().map_or("", |u| u))
+                }
+                FormOption::LdapGroup => {
+                    format!("ldap-group:{}", self.ldap_group.as_ref().map_or("", |g| g))
+                }
+                _ => "raw pubkey".to_string(),
+            },
+        };
+        format!("{} ({})", entry, &self.name)
+    }
+}
+
+#[derive(Serialize)]
+struct IndexContext {
+    invitation: Option<String>,
+    gitea_instances: Vec<String>,
+    ldap: bool,
+    oidc: bool,
+    oidc_user: Option<String>,
+    csrf: String,
+}
+
+#[derive(FromForm)]
+struct InvitationInput {
+    max_uses: u32,
+    valid_hours: Option<i64>,
+}
+
+#[derive(FromForm)]
+struct RevokeInput {
+    token: String,
+}
+
+#[derive(FromForm)]
+struct ReviewInput {
+    raw: bool,
+    entry: String,
+}
+
+#[derive(FromForm)]
+struct RoleInput {
+    principal: String,
+    role: String,
+}
+
+#[derive(FromForm)]
+struct RoleRevokeInput {
+    principal: String,
+}
+
+#[derive(FromForm)]
+struct LinkInput {
+    valid_hours: i64,
+}
+
+#[derive(Serialize)]
+struct DeployGroup {
+    group: &'static str,
+    destinations: Vec<String>,
+    deploy: bool,
+}
+
+#[derive(Serialize)]
+struct ReviewGroup {
+    group: &'static str,
+    entries: Vec<storage::Entry>,
+}
+
+#[derive(FromForm)]
+struct LoginInput {
+    username: String,
+    password: String,
+}
+
+/// What a submission of the web form led to.
+enum Submitted {
+    /// The entry is stored, with the message for the submitter.
+    Done(String),
+    /// The entry waits until the submitter signs the challenge.
+    Challenge(challenge::ChallengeContext),
 }
 
 #[post("/", data = "<form>")]
-fn index_post(form: Result<Form<FormInput>, FormError>) -> Template {
-    match form {
+fn index_post(
+    form: csrf::CsrfForm<FormInput>,
+    session: Option<roles::Principal>,
+    oidc_user: Option<oidc::OidcUser>,
+    limit: ratelimit::RateLimit,
+    client: audit::Client,
+) -> Template {
+    match form.0 {
         Ok(form) => {
             let config = &*CONFIG.lock().unwrap();
-            let admin = if form.authkey == config.admin_psk {
-                true
-            } else if &form.authkey == &config.user_psk {
-                false
-            } else {
-                return Template::render("insert_result", &format!("Wrong authkey: {:?}", form));
+            // submissions of anyone but the group's reviewers wait for approval
+            let target = |admin| Target {
+                admin,
+                pending: config.require_approval
+                    && !session.as_ref().is_some_and(|p| {
+                        p.has_role(roles::group_name(admin), roles::Role::Reviewer)
+                    }),
             };
-            println!("authkey {} admin={}", &form.authkey, admin);
-            if form.radio == FormOption::GitHub {
-                match storage::handle_submission("github", &form.github_username, &form.name, admin)
-                {
-                    Ok(_) => Template::render(
-                        "insert_result",
-                        &format!("Successfully added github user {:?}", &form.github_username),
-                    ),
-                    Err(e) => Template::render("insert_result", &format!("ERROR: {:?}", e)),
-                }
-            } else if form.radio == FormOption::TubLab {
-                match storage::handle_submission("tublab", &form.tublab_username, &form.name, admin)
-                {
-                    Ok(_) => Template::render(
-                        "insert_result",
-                        &format!(
-                            "Successfully added tubit gitlab user {:?}",
-                            &form.tublab_username
-                        ),
-                    ),
-                    Err(e) => Template::render("insert_result", &format!("ERROR: {:?}", e)),
-                }
-            } else if form.radio == FormOption::GitLab {
-                match storage::handle_submission("gitlab", &form.gitlab_username, &form.name, admin)
-                {
-                    Ok(_) => Template::render(
+            let submitter_groups = session
+                .as_ref()
+                .map(|p| p.groups(roles::Role::Submitter))
+                .unwrap_or_default();
+            let session_name = session.as_ref().map_or("", |p| p.name.as_str());
+            let mut invitation = None;
+            let (admin, who) = if submitter_groups.contains(&roles::GROUPS[0]) {
+                (true, session_name.to_string())
+            } else if auth::constant_time_eq(&form.authkey, &config.admin_psk) {
+                (true, "admin-psk".to_string())
+            } else if !submitter_groups.is_empty() {
+                (false, session_name.to_string())
+            } else if auth::constant_time_eq(&form.authkey, &config.user_psk) {
+                (false, "user-psk".to_string())
+            } else if let Ok(admin) = invitations::check(&form.authkey) {
+                // the invitation is only used up once the submission is stored
+                invitation = Some(form.authkey.clone());
+                let token: String = form.authkey.chars().take(8).collect();
+                (admin, format!("invitation:{}", token))
+            } else if let (Some(user), Some(oidc)) = (&oidc_user, &config.oidc) {
+                // submitters logged in via OpenID Connect may only add their own identity
+                if form.provider_user() != Some((oidc.provider.as_str(), user.username.as_str())) {
+                    return Template::render(
                         "insert_result",
-                        &format!(
-                            "Successfully added gitlab.com user {:?}",
-                            &form.gitlab_username
+                        format!(
+                            "ERROR: you can only submit your own {} user {:?}",
+                            &oidc.provider, &user.username
                         ),
-                    ),
-                    Err(e) => Template::render("insert_result", &format!("ERROR: {:?}", e)),
+                    );
                 }
-            } else if form.radio == FormOption::EnoLab {
-                match storage::handle_submission("enolab", &form.enolab_username, &form.name, admin)
-                {
-                    Ok(_) => Template::render(
-                        "insert_result",
-                        &format!(
-                            "Successfully added enoflag gitlab user {:?}",
-                            &form.enolab_username
-                        ),
-                    ),
-                    Err(e) => Template::render("insert_result", &format!("ERROR: {:?}", e)),
+                // the login verified the identity, no challenge needed
+                let submitted = store_provider(
+                    &oidc.provider,
+                    &user.username,
+                    &form.name,
+                    target(false),
+                    &format!("{} user", &oidc.provider),
+                );
+                if submitted.is_ok() {
+                    audit::append(&audit::Record::new(
+                        &format!("{}{}", roles::OIDC_PREFIX, &user.username),
+                        &client,
+                        "submit",
+                        Some(roles::GROUPS[1]),
+                        &format!("{}{}", form.describe(), target(false).note()),
+                    ));
                 }
-            } else if form.radio == FormOption::PubKey {
-                match storage::handle_raw_submission(&form.name, &form.pub_key, admin) {
-                    Ok(_) => Template::render(
-                        "insert_result",
-                        &format!("Successfully added raw pubkey{:?}", &form.pub_key),
-                    ),
-                    Err(e) => Template::render("insert_result", &format!("ERROR: {:?}", e)),
+                return render_submitted(submitted);
+            } else {
+                limit.failure();
+                audit::append(&audit::Record::new(
+                    "anonymous",
+                    &client,
+                    "authkey-failure",
+                    None,
+                    &form.describe(),
+                ));
+                return Template::render("insert_result", "Wrong authkey");
+            };
+            let target = target(admin);
+            let submitted = submit_form(config, &form, target, invitation.as_deref());
+            if submitted.is_ok() {
+                let mut record = audit::Record::new(
+                    &who,
+                    &client,
+                    "submit",
+                    Some(roles::group_name(admin)),
+                    &format!("{}{}", form.describe(), target.note()),
+                );
+                if form.radio == FormOption::PubKey {
+                    record.fingerprints = audit::fingerprints(&form.pub_key);
                 }
+                audit::append(&record);
+            }
+            if let (Ok(Submitted::Done(_)), Some(token)) = (&submitted, &invitation) {
+                use_invitation(token, &form.describe());
+            }
+            render_submitted(submitted)
+        }
+        Err(e) => Template::render("insert_result", format!("Invalid form input: {}", e)),
+    }
+}
+
+fn render_submitted(submitted: Result<Submitted, EnokeysError>) -> Template {
+    match submitted {
+        Ok(Submitted::Done(message)) => Template::render("insert_result", message),
+        Ok(Submitted::Challenge(context)) => Template::render("challenge", &context),
+        Err(e) => Template::render("insert_result", format!("ERROR: {:?}", e)),
+    }
+}
+
+/// Records the stored submission in the invitation, which was checked before.
+fn use_invitation(token: &str, submission: &str) {
+    if let Err(e) = invitations::redeem(token, submission) {
+        warn!("Could not record the use of an invitation: {:?}", e);
+    }
+}
+
+/// Stores the form's entry, or issues a challenge for it if keys must be verified.
+fn submit_form(
+    config: &Context,
+    form: &FormInput,
+    target: Target,
+    invitation: Option<&str>,
+) -> Result<Submitted, EnokeysError> {
+    let provider = |provider: &str, user_name: &str, description: &str| {
+        submit_provider(
+            config,
+            provider,
+            user_name,
+            &form.name,
+            target,
+            description,
+            invitation,
+        )
+    };
+    match form.radio {
+        FormOption::GitHub => provider("github", &form.github_username, "github user"),
+        FormOption::TubLab => provider("tublab", &form.tublab_username, "tubit gitlab user"),
+        FormOption::GitLab => provider("gitlab", &form.gitlab_username, "gitlab.com user"),
+        FormOption::EnoLab => provider("enolab", &form.enolab_username, "enoflag gitlab user"),
+        FormOption::Codeberg => provider("codeberg", &form.codeberg_username, "codeberg user"),
+        FormOption::SourceHut => provider("sourcehut", &form.sourcehut_username, "sourcehut user"),
+        FormOption::GitLabGroup => {
+            storage::handle_group_submission(
+                &form.gitlab_group_instance,
+                &form.gitlab_group,
+                form.gitlab_group_access_level,
+                &form.name,
+                target.admin,
+                target.pending,
+            )?;
+            Ok(Submitted::Done(format!(
+                "Successfully added {} gitlab group {:?}{}",
+                &form.gitlab_group_instance,
+                &form.gitlab_group,
+                target.note()
+            )))
+        }
+        FormOption::Gitea => {
+            let instance = form.gitea_instance.clone().unwrap_or_default();
+            let username = form.gitea_username.clone().unwrap_or_default();
+            if !config.gitea_instances.contains_key(&instance) {
+                return Err(EnokeysError::InvalidProviderError(instance));
+            }
+            provider(&instance, &username, &format!("{} user", &instance))
+        }
+        FormOption::Ldap | FormOption::LdapGroup => {
+            let (provider, entry) = if form.radio == FormOption::Ldap {
+                (ldap::LDAP, form.ldap_username.clone().unwrap_or_default())
             } else {
-                Template::render("insert_result", &format!("ERROR: {:?}", form))
+                (
+                    ldap::LDAP_GROUP,
+                    form.ldap_group.clone().unwrap_or_default(),
+                )
+            };
+            if config.ldap.is_none() {
+                return Err(EnokeysError::InvalidProviderError(provider.to_string()));
+            }
+            storage::handle_submission(provider, &entry, &form.name, target.admin, target.pending)?;
+            Ok(Submitted::Done(format!(
+                "Successfully added {} entry {:?}{}",
+                provider,
+                &entry,
+                target.note()
+            )))
+        }
+        FormOption::PubKey => {
+            if config.verify_raw_keys {
+                let pub_key = storage::parse_raw_key(&form.pub_key)?;
+                let nonce = challenge::issue(
+                    challenge::Submission::Raw {
+                        pub_key: pub_key.clone(),
+                        name: form.name.to_string(),
+                        admin: target.admin,
+                        pending: target.pending,
+                        invitation: invitation.map(|i| i.to_string()),
+                    },
+                    vec![pub_key],
+                );
+                return Ok(Submitted::Challenge(challenge::ChallengeContext {
+                    nonce,
+                    description: "the submitted raw pubkey".to_string(),
+                    namespace: challenge::NAMESPACE,
+                }));
             }
+            storage::handle_raw_submission(
+                &form.name,
+                &form.pub_key,
+                target.admin,
+                target.pending,
+            )?;
+            Ok(Submitted::Done(format!(
+                "Successfully added raw pubkey{:?}{}",
+                &form.pub_key,
+                target.note()
+            )))
         }
-        Err(e) => Template::render("insert_result", &format!("Invalid form input: {:?}", e)),
     }
 }
 
-#[get("/")]
-fn index_get() -> io::Result<NamedFile> {
-    NamedFile::open("static/index.html")
+/// Stores a provider entry, or issues a challenge for it if providers are verified.
+/// The `invitation` is used up once the challenge is signed.
+fn submit_provider(
+    config: &Context,
+    provider: &str,
+    user_name: &str,
+    name: &str,
+    target: Target,
+    description: &str,
+    invitation: Option<&str>,
+) -> Result<Submitted, EnokeysError> {
+    if config.verify_providers {
+        let user_name = USERNAME_REGEX.replace_all(user_name, "").to_string();
+        let keys = scraper::fetch(&user_name, provider, &config.gitea_instances)?;
+        if keys.is_empty() {
+            return Err(EnokeysError::InvalidData(format!(
+                "no keys found for {} {:?}",
+                description, &user_name
+            )));
+        }
+        let nonce = challenge::issue(
+            challenge::Submission::Provider {
+                provider: provider.to_string(),
+                user_name: user_name.clone(),
+                name: name.to_string(),
+                admin: target.admin,
+                pending: target.pending,
+                invitation: invitation.map(|i| i.to_string()),
+            },
+            keys,
+        );
+        return Ok(Submitted::Challenge(challenge::ChallengeContext {
+            nonce,
+            description: format!("{} {:?}", description, &user_name),
+            namespace: challenge::NAMESPACE,
+        }));
+    }
+    store_provider(provider, user_name, name, target, description)
 }
 
-#[post("/deploy", data = "<form>")]
-fn deploy_post(form: Result<Form<DeployInput>, FormError>) -> content::Html<String> {
-    content::Html(match form {
+fn store_provider(
+    provider: &str,
+    user_name: &str,
+    name: &str,
+    target: Target,
+    description: &str,
+) -> Result<Submitted, EnokeysError> {
+    storage::handle_submission(provider, user_name, name, target.admin, target.pending)?;
+    Ok(Submitted::Done(format!(
+        "Successfully added {} {:?}{}",
+        description,
+        user_name,
+        target.note()
+    )))
+}
+
+// no CSRF token needed, the signature of the challenge authenticates the request
+#[post("/verify", data = "<form>")]
+fn verify_post(
+    form: Result<Form<VerifyInput>, FormError>,
+    _limit: ratelimit::RateLimit,
+    client: audit::Client,
+) -> Template {
+    match form {
         Ok(form) => {
-            let config = &*CONFIG.lock().unwrap();
-            if form.authkey != config.admin_psk {
-                return content::Html(format!("Wrong AUTHKEY: {:?}", form));
-            };
-            let admin_result = deploy::deploy(
-                &config.admin_destinations,
-                &ADMIN_DESTINATIONS_AUTHORIZED_KEYS,
-            );
-            let user_result = deploy::deploy(
-                &config.user_destinations,
-                &USER_DESTINATIONS_AUTHORIZED_KEYS,
-            );
-            format!(
-                "deployed admin: {:?}\n<br/>\ndeployed user: {:?}",
-                admin_result, user_result
-            )
+            match challenge::redeem(&form.challenge, &form.signature).and_then(|submission| {
+                // the invitation may have been used up while the challenge was open
+                if let Some(token) = submission.invitation() {
+                    invitations::check(token)?;
+                }
+                Ok(submission)
+            }) {
+                Ok(challenge::Submission::Provider {
+                    provider,
+                    user_name,
+                    name,
+                    admin,
+                    pending,
+                    invitation,
+                }) => {
+                    match storage::handle_submission(&provider, &user_name, &name, admin, pending) {
+                        Ok(_) => {
+                            if let Some(ref token) = invitation {
+                                use_invitation(
+                                    token,
+                                    &format!("{}:{} ({})", &provider, &user_name, &name),
+                                );
+                            }
+                            audit::append(&audit::Record::new(
+                                &format!("{}:{}", &provider, &user_name),
+                                &client,
+                                "verify",
+                                Some(roles::group_name(admin)),
+                                &format!(
+                                    "{} ({}){}",
+                                    &provider,
+                                    &name,
+                                    Target { admin, pending }.note()
+                                ),
+                            ));
+                            Template::render(
+                                "insert_result",
+                                format!(
+                                    "Successfully verified and added {} user {:?}{}",
+                                    &provider,
+                                    &user_name,
+                                    Target { admin, pending }.note()
+                                ),
+                            )
+                        }
+                        Err(e) => Template::render("insert_result", format!("ERROR: {:?}", e)),
+                    }
+                }
+                Ok(challenge::Submission::Raw {
+                    pub_key,
+                    name,
+                    admin,
+                    pending,
+                    invitation,
+                }) => match storage::handle_raw_submission(&name, &pub_key, admin, pending) {
+                    Ok(_) => {
+                        if let Some(ref token) = invitation {
+                            use_invitation(token, &format!("raw pubkey ({})", &name));
+                        }
+                        let mut record = audit::Record::new(
+                            "raw",
+                            &client,
+                            "verify",
+                            Some(roles::group_name(admin)),
+                            &format!("raw pubkey ({}){}", &name, Target { admin, pending }.note()),
+                        );
+                        record.fingerprints = audit::fingerprints(&pub_key);
+                        audit::append(&record);
+                        Template::render(
+                            "insert_result",
+                            format!(
+                                "Successfully verified and added raw pubkey {:?}{}",
+                                &pub_key,
+                                Target { admin, pending }.note()
+                            ),
+                        )
+                    }
+                    Err(e) => Template::render("insert_result", format!("ERROR: {:?}", e)),
+                },
+                Err(e) => Template::render("insert_result", format!("ERROR: {:?}", e)),
+            }
         }
-        Err(e) => format!("Invalid form input: {:?}", e),
-    })
+        Err(e) => Template::render("insert_result", format!("Invalid form input: {:?}", e)),
+    }
 }
 
-#[get("/deploy")]
-fn deploy_get() -> Template {
+#[get("/?<invitation>")]
+fn index_get(
+    invitation: Option<String>,
+    oidc_user: Option<oidc::OidcUser>,
+    csrf: csrf::CsrfToken,
+) -> Template {
+    let config = &*CONFIG.lock().unwrap();
+    let mut gitea_instances: Vec<String> = config.gitea_instances.keys().cloned().collect();
+    gitea_instances.sort();
+    Template::render(
+        "index",
+        &IndexContext {
+            invitation,
+            gitea_instances,
+            ldap: config.ldap.is_some(),
+            oidc: config.oidc.is_some(),
+            oidc_user: oidc_user.map(|u| u.username),
+            csrf: csrf.0,
+        },
+    )
+}
+
+#[post("/deploy/<group>", data = "<_csrf>")]
+fn deploy_post(
+    group: String,
+    _limit: ratelimit::RateLimit,
+    _csrf: csrf::CsrfForm<csrf::NoInput>,
+    deployer: roles::Deployer,
+    client: audit::Client,
+) -> content::Html<String> {
     let config = &*CONFIG.lock().unwrap();
-    storage::generate_authorized_key_files().unwrap();
+    let hosts = deploy_group(config, deployer.admin(), &deployer.principal.name, &client);
+    content::Html(format!(
+        "deployed {}:\n<br/>\n{}",
+        group,
+        hosts
+            .iter()
+            .map(|h| format!("{}: {}", &h.destination, &h.message))
+            .collect::<Vec<String>>()
+            .join("\n<br/>\n")
+    ))
+}
+
+/// Deploys the group's authorized_keys to its destinations and audits the outcome.
+fn deploy_group(
+    config: &Context,
+    admin: bool,
+    who: &str,
+    client: &audit::Client,
+) -> Vec<deploy::HostOutcome> {
+    let destinations = if admin {
+        &config.admin_destinations
+    } else {
+        &config.user_destinations
+    };
+    let file = authorized_keys_file(admin);
+    let hosts = deploy::deploy(roles::group_name(admin), destinations, file);
+    let mut record = audit::Record::new(
+        who,
+        client,
+        "deploy",
+        Some(roles::group_name(admin)),
+        &format!(
+            "{}/{} destinations",
+            hosts.iter().filter(|h| h.success).count(),
+            hosts.len()
+        ),
+    );
+    record.fingerprints = audit::fingerprints(&fs::read_to_string(file).unwrap_or_default());
+    record.hosts = hosts.clone();
+    audit::append(&record);
+    hosts
+}
+
+#[get("/invitations")]
+fn invitations_get(principal: roles::Principal, csrf: csrf::CsrfToken) -> Result<Template, Status> {
+    let groups = principal.groups(roles::Role::Reviewer);
+    if groups.is_empty() {
+        return Err(Status::Forbidden);
+    }
+    match invitations::list() {
+        Ok(invitations) => {
+            let invitations: Vec<invitations::Invitation> = invitations
+                .into_iter()
+                .filter(|i| groups.contains(&roles::group_name(i.admin)))
+                .collect();
+            let mut context = HashMap::new();
+            context.insert("invitations", serde_json::to_value(invitations).unwrap());
+            context.insert("groups", serde_json::to_value(groups).unwrap());
+            context.insert("csrf", serde_json::to_value(&csrf.0).unwrap());
+            Ok(Template::render("invitations", &context))
+        }
+        Err(e) => Ok(Template::render("insert_result", format!("ERROR: {:?}", e))),
+    }
+}
+
+#[post("/invitations/<group>", data = "<form>")]
+fn invitations_post(
+    group: String,
+    form: csrf::CsrfForm<InvitationInput>,
+    _limit: ratelimit::RateLimit,
+    reviewer: roles::Reviewer,
+    client: audit::Client,
+) -> Result<Redirect, Template> {
+    match form.0 {
+        Ok(form) => match invitations::create(
+            reviewer.admin(),
+            form.max_uses,
+            form.valid_hours,
+            &reviewer.principal.name,
+        ) {
+            Ok(invitation) => {
+                audit::append(&audit::Record::new(
+                    &reviewer.principal.name,
+                    &client,
+                    "invitation-create",
+                    Some(&group),
+                    &format!(
+                        "{}... for {} uses",
+                        invitation.token.chars().take(8).collect::<String>(),
+                        invitation.max_uses
+                    ),
+                ));
+                Ok(Redirect::to("/invitations"))
+            }
+            Err(e) => Err(Template::render("insert_result", format!("ERROR: {:?}", e))),
+        },
+        Err(e) => Err(Template::render(
+            "insert_result",
+            format!("Invalid form input for {}: {}", group, e),
+        )),
+    }
+}
+
+#[post("/invitations/<group>/revoke", data = "<form>")]
+fn invitations_revoke(
+    group: String,
+    form: csrf::CsrfForm<RevokeInput>,
+    _limit: ratelimit::RateLimit,
+    reviewer: roles::Reviewer,
+    client: audit::Client,
+) -> Result<Redirect, Template> {
+    match form.0 {
+        Ok(form) => match invitations::revoke(&form.token, reviewer.admin()) {
+            Ok(_) => {
+                audit::append(&audit::Record::new(
+                    &reviewer.principal.name,
+                    &client,
+                    "invitation-revoke",
+                    Some(&group),
+                    &format!("{}...", form.token.chars().take(8).collect::<String>()),
+                ));
+                Ok(Redirect::to("/invitations"))
+            }
+            Err(e) => Err(Template::render("insert_result", format!("ERROR: {:?}", e))),
+        },
+        Err(e) => Err(Template::render(
+            "insert_result",
+            format!("Invalid form input for {}: {}", group, e),
+        )),
+    }
+}
+
+#[get("/review")]
+fn review_get(principal: roles::Principal, csrf: csrf::CsrfToken) -> Result<Template, Status> {
+    let groups = principal.groups(roles::Role::Reviewer);
+    if groups.is_empty() {
+        return Err(Status::Forbidden);
+    }
+    let mut review_groups = vec![];
+    for group in groups {
+        match storage::pending_entries(group == roles::GROUPS[0]) {
+            Ok(entries) => review_groups.push(ReviewGroup { group, entries }),
+            Err(e) => return Ok(Template::render("insert_result", format!("ERROR: {:?}", e))),
+        }
+    }
     let mut context = HashMap::new();
-    let admin_destinations: Vec<String> = config
-        .admin_destinations
-        .iter()
-        .map(|a| a.destination_name.to_string())
-        .collect();
-    let user_destinations: Vec<String> = config
-        .user_destinations
-        .iter()
-        .map(|a| a.destination_name.to_string())
+    context.insert("groups", serde_json::to_value(review_groups).unwrap());
+    context.insert("csrf", serde_json::to_value(&csrf.0).unwrap());
+    Ok(Template::render("review", &context))
+}
+
+fn review(
+    form: Result<ReviewInput, String>,
+    reviewer: &roles::Reviewer,
+    client: &audit::Client,
+    approve: bool,
+) -> Result<Redirect, Template> {
+    match form {
+        Ok(form) => match storage::review_entry(reviewer.admin(), form.raw, &form.entry, approve) {
+            Ok(_) => {
+                let mut record = audit::Record::new(
+                    &reviewer.principal.name,
+                    client,
+                    if approve { "approve" } else { "reject" },
+                    Some(&reviewer.group),
+                    form.entry.trim(),
+                );
+                record.fingerprints = audit::fingerprints(&form.entry);
+                audit::append(&record);
+                Ok(Redirect::to("/review"))
+            }
+            Err(e) => Err(Template::render("insert_result", format!("ERROR: {:?}", e))),
+        },
+        Err(e) => Err(Template::render(
+            "insert_result",
+            format!("Invalid form input for {}: {}", &reviewer.group, e),
+        )),
+    }
+}
+
+#[post("/review/<group>/approve", data = "<form>")]
+fn review_approve(
+    group: String,
+    form: csrf::CsrfForm<ReviewInput>,
+    _limit: ratelimit::RateLimit,
+    reviewer: roles::Reviewer,
+    client: audit::Client,
+) -> Result<Redirect, Template> {
+    info!(reviewer = %reviewer.principal.name, group = %group, "approving entry");
+    review(form.0, &reviewer, &client, true)
+}
+
+#[post("/review/<group>/reject", data = "<form>")]
+fn review_reject(
+    group: String,
+    form: csrf::CsrfForm<ReviewInput>,
+    _limit: ratelimit::RateLimit,
+    reviewer: roles::Reviewer,
+    client: audit::Client,
+) -> Result<Redirect, Template> {
+    info!(reviewer = %reviewer.principal.name, group = %group, "rejecting entry");
+    review(form.0, &reviewer, &client, false)
+}
+
+#[get("/roles")]
+fn roles_get(principal: roles::Principal, csrf: csrf::CsrfToken) -> Result<Template, Status> {
+    let groups = principal.groups(roles::Role::Owner);
+    if groups.is_empty() {
+        return Err(Status::Forbidden);
+    }
+    match roles::list() {
+        Ok(assignments) => {
+            let assignments: Vec<roles::Assignment> = assignments
+                .into_iter()
+                .filter(|a| groups.contains(&a.group.as_str()))
+                .collect();
+            let mut context = HashMap::new();
+            context.insert("assignments", serde_json::to_value(assignments).unwrap());
+            context.insert("groups", serde_json::to_value(groups).unwrap());
+            context.insert("csrf", serde_json::to_value(&csrf.0).unwrap());
+            Ok(Template::render("roles", &context))
+        }
+        Err(e) => Ok(Template::render("insert_result", format!("ERROR: {:?}", e))),
+    }
+}
+
+#[post("/roles/<group>", data = "<form>")]
+fn roles_post(
+    group: String,
+    form: csrf::CsrfForm<RoleInput>,
+    _limit: ratelimit::RateLimit,
+    owner: roles::Owner,
+    client: audit::Client,
+) -> Result<Redirect, Template> {
+    match form.0 {
+        Ok(form) => match roles::Role::parse(&form.role)
+            .and_then(|role| roles::grant(&form.principal, &group, role))
+        {
+            Ok(_) => {
+                audit::append(&audit::Record::new(
+                    &owner.principal.name,
+                    &client,
+                    "role-grant",
+                    Some(&group),
+                    &format!("{} as {}", &form.principal, &form.role),
+                ));
+                Ok(Redirect::to("/roles"))
+            }
+            Err(e) => Err(Template::render("insert_result", format!("ERROR: {:?}", e))),
+        },
+        Err(e) => Err(Template::render(
+            "insert_result",
+            format!("Invalid form input: {}", e),
+        )),
+    }
+}
+
+#[post("/roles/<group>/revoke", data = "<form>")]
+fn roles_revoke(
+    group: String,
+    form: csrf::CsrfForm<RoleRevokeInput>,
+    _limit: ratelimit::RateLimit,
+    owner: roles::Owner,
+    client: audit::Client,
+) -> Result<Redirect, Template> {
+    match form.0 {
+        Ok(form) => match roles::revoke(&form.principal, &group) {
+            Ok(_) => {
+                audit::append(&audit::Record::new(
+                    &owner.principal.name,
+                    &client,
+                    "role-revoke",
+                    Some(&group),
+                    &form.principal,
+                ));
+                Ok(Redirect::to("/roles"))
+            }
+            Err(e) => Err(Template::render("insert_result", format!("ERROR: {:?}", e))),
+        },
+        Err(e) => Err(Template::render(
+            "insert_result",
+            format!("Invalid form input: {}", e),
+        )),
+    }
+}
+
+#[get("/login")]
+fn login_get(csrf: csrf::CsrfToken) -> Template {
+    let mut context = HashMap::new();
+    context.insert("csrf", csrf.0);
+    Template::render("login", &context)
+}
+
+#[post("/login", data = "<form>")]
+fn login_post(
+    form: csrf::CsrfForm<LoginInput>,
+    limit: ratelimit::RateLimit,
+    client: audit::Client,
+    mut cookies: Cookies,
+) -> Result<Redirect, Template> {
+    match form.0 {
+        Ok(form) => match auth::login(&form.username, &form.password, &mut cookies) {
+            Ok(_) => {
+                audit::append(&audit::Record::new(
+                    &form.username,
+                    &client,
+                    "login",
+                    None,
+                    "",
+                ));
+                Ok(Redirect::to("/deploy"))
+            }
+            Err(e) => {
+                limit.failure();
+                audit::append(&audit::Record::new(
+                    &form.username,
+                    &client,
+                    "login-failure",
+                    None,
+                    "",
+                ));
+                Err(Template::render("insert_result", format!("ERROR: {:?}", e)))
+            }
+        },
+        Err(e) => Err(Template::render(
+            "insert_result",
+            format!("Invalid form input: {}", e),
+        )),
+    }
+}
+
+#[post("/logout", data = "<_csrf>")]
+fn logout_post(_csrf: csrf::CsrfForm<csrf::NoInput>, mut cookies: Cookies) -> Redirect {
+    auth::logout(&mut cookies);
+    oidc::logout(&mut cookies);
+    Redirect::to("/")
+}
+
+#[get("/oidc/login")]
+fn oidc_login(mut cookies: Cookies) -> Result<Redirect, Template> {
+    let config = &*CONFIG.lock().unwrap();
+    let oidc = match config.oidc {
+        Some(ref oidc) => oidc,
+        None => {
+            return Err(Template::render(
+                "insert_result",
+                "OpenID Connect is not configured",
+            ))
+        }
+    };
+    match oidc::authorization_url(oidc, &mut cookies) {
+        Ok(url) => Ok(Redirect::to(url)),
+        Err(e) => Err(Template::render("insert_result", format!("ERROR: {:?}", e))),
+    }
+}
+
+#[get("/oidc/callback?<code>&<state>")]
+fn oidc_callback(code: String, state: String, mut cookies: Cookies) -> Result<Redirect, Template> {
+    let config = &*CONFIG.lock().unwrap();
+    let oidc = match config.oidc {
+        Some(ref oidc) => oidc,
+        None => {
+            return Err(Template::render(
+                "insert_result",
+                "OpenID Connect is not configured",
+            ))
+        }
+    };
+    match oidc::callback(oidc, &code, &state, &mut cookies) {
+        Ok(_) => Ok(Redirect::to("/")),
+        Err(e) => Err(Template::render("insert_result", format!("ERROR: {:?}", e))),
+    }
+}
+
+#[get("/deploy")]
+fn deploy_get(principal: roles::Principal, csrf: csrf::CsrfToken) -> Template {
+    let config = &*CONFIG.lock().unwrap();
+    if let Err(e) = storage::generate_authorized_key_files(config) {
+        return Template::render("insert_result", format!("ERROR: {:?}", e));
+    }
+    // destinations and keys are only shown to the group's reviewers
+    let groups: Vec<DeployGroup> = principal
+        .groups(roles::Role::Reviewer)
+        .into_iter()
+        .map(|group| DeployGroup {
+            group,
+            destinations: if group == roles::GROUPS[0] {
+                &config.admin_destinations
+            } else {
+                &config.user_destinations
+            }
+            .iter()
+            .map(|d| d.destination_name.to_string())
+            .collect(),
+            deploy: principal.has_role(group, roles::Role::Deployer),
+        })
         .collect();
-    context.insert("admin_destinations", admin_destinations);
-    context.insert("user_destinations", user_destinations);
+    let mut context = HashMap::new();
+    context.insert("session", serde_json::to_value(&principal.name).unwrap());
+    context.insert("csrf", serde_json::to_value(&csrf.0).unwrap());
+    context.insert("review", serde_json::to_value(!groups.is_empty()).unwrap());
+    context.insert("groups", serde_json::to_value(groups).unwrap());
+    context.insert(
+        "owner",
+        serde_json::to_value(!principal.groups(roles::Role::Owner).is_empty()).unwrap(),
+    );
     Template::render("deploy", &context)
 }
 
+#[get("/keyfiles/<file>?<expires>&<signature>")]
+fn keyfile_get(
+    file: String,
+    expires: Option<i64>,
+    signature: Option<String>,
+    principal: Option<roles::Principal>,
+    client: audit::Client,
+) -> Result<NamedFile, Status> {
+    let group = match file.strip_suffix(".authorized_keys") {
+        Some(group) if roles::GROUPS.contains(&group) => group,
+        _ => return Err(Status::NotFound),
+    };
+    let who = match (expires, signature, principal) {
+        (Some(expires), Some(signature), _) => {
+            if !links::verify(&file, expires, &signature) {
+                return Err(Status::Forbidden);
+            }
+            "signed-link".to_string()
+        }
+        (_, _, Some(principal)) => {
+            if !principal.has_role(group, roles::Role::Reviewer) {
+                return Err(Status::Forbidden);
+            }
+            principal.name
+        }
+        _ => return Err(Status::Unauthorized),
+    };
+    audit::append(&audit::Record::new(
+        &who,
+        &client,
+        "download",
+        Some(group),
+        &file,
+    ));
+    NamedFile::open(authorized_keys_file(group == roles::GROUPS[0])).map_err(|_| Status::NotFound)
+}
+
+#[post("/keyfiles/<group>/link", data = "<form>")]
+fn keyfile_link(
+    group: String,
+    form: csrf::CsrfForm<LinkInput>,
+    _limit: ratelimit::RateLimit,
+    reviewer: roles::Reviewer,
+    client: audit::Client,
+) -> Template {
+    let file = format!("{}.authorized_keys", &group);
+    match form.0 {
+        Ok(form) => match links::sign(&file, form.valid_hours) {
+            Ok((expires, signature)) => {
+                audit::append(&audit::Record::new(
+                    &reviewer.principal.name,
+                    &client,
+                    "link-create",
+                    Some(&group),
+                    &format!("{} valid for {} hours", &file, form.valid_hours),
+                ));
+                Template::render(
+                    "insert_result",
+                    format!(
+                        "Download link valid for {} hours: /keyfiles/{}?expires={}&signature={}",
+                        form.valid_hours, &file, expires, &signature
+                    ),
+                )
+            }
+            Err(e) => Template::render("insert_result", format!("ERROR: {:?}", e)),
+        },
+        Err(e) => Template::render("insert_result", format!("Invalid form input: {}", e)),
+    }
+}
+
+/// The audit records of the principal's owned groups, and those without a group.
+fn audit_records(
+    principal: &roles::Principal,
+    filter: &audit::Filter,
+) -> Result<Vec<audit::Record>, Status> {
+    let groups = principal.groups(roles::Role::Owner);
+    if groups.is_empty() {
+        return Err(Status::Forbidden);
+    }
+    match audit::list(filter) {
+        Ok(records) => Ok(records
+            .into_iter()
+            .filter(|r| r.group.as_deref().is_none_or(|g| groups.contains(&g)))
+            .collect()),
+        Err(e) => {
+            error!("Could not read audit log: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[get("/audit?<filter..>")]
+fn audit_get(filter: Form<audit::Filter>, principal: roles::Principal) -> Result<Template, Status> {
+    let mut records = audit_records(&principal, &filter)?;
+    records.reverse();
+    let query: Vec<String> = [
+        ("who", &filter.who),
+        ("action", &filter.action),
+        ("group", &filter.group),
+    ]
+    .iter()
+    .filter_map(|(key, value)| {
+        value
+            .as_ref()
+            .filter(|v| !v.is_empty())
+            .map(|v| format!("{}={}", key, Uri::percent_encode(v)))
+    })
+    .collect();
+    let mut context = HashMap::new();
+    context.insert("records", serde_json::to_value(records).unwrap());
+    context.insert("filter", serde_json::to_value(&*filter).unwrap());
+    context.insert("query", serde_json::to_value(query.join("&")).unwrap());
+    Ok(Template::render("audit", &context))
+}
+
+#[get("/audit/export?<filter..>")]
+fn audit_export(
+    filter: Form<audit::Filter>,
+    principal: roles::Principal,
+) -> Result<content::Content<String>, Status> {
+    let mut export = String::new();
+    for record in audit_records(&principal, &filter)? {
+        export.push_str(&serde_json::to_string(&record).unwrap());
+        export.push('\n');
+    }
+    Ok(content::Content(
+        ContentType::new("application", "x-ndjson"),
+        export,
+    ))
+}
+
+fn authorized_keys_file(admin: bool) -> &'static PathBuf {
+    if admin {
+        &ADMIN_DESTINATIONS_AUTHORIZED_KEYS
+    } else {
+        &USER_DESTINATIONS_AUTHORIZED_KEYS
+    }
+}
+
+#[catch(401)]
+fn unauthorized(request: &Request) -> Result<Redirect, api::ApiError> {
+    if api::is_api(request) {
+        return Err(api::ApiError(
+            Status::Unauthorized,
+            "missing or invalid API token".to_string(),
+        ));
+    }
+    Ok(Redirect::to("/login"))
+}
+
+#[catch(429)]
+fn too_many_requests(request: &Request) -> Result<Template, api::ApiError> {
+    let message = "Too many requests, try again later";
+    if api::is_api(request) {
+        return Err(api::ApiError(Status::TooManyRequests, message.to_string()));
+    }
+    Ok(Template::render("insert_result", message))
+}
+
+#[catch(403)]
+fn forbidden(request: &Request) -> Result<Template, api::ApiError> {
+    let message = "Forbidden: your roles do not allow this";
+    if api::is_api(request) {
+        return Err(api::ApiError(Status::Forbidden, message.to_string()));
+    }
+    Ok(Template::render("insert_result", message))
+}
+
+#[get("/metrics")]
+fn metrics_get(_auth: metrics::MetricsAuth) -> content::Plain<String> {
+    content::Plain(metrics::render())
+}
+
+/// The host CA's `known_hosts` line. With a configured host pattern it is public,
+/// otherwise it names the destinations of the groups the account submits to.
+#[get("/ca/known_hosts")]
+fn known_hosts_get(principal: Option<roles::Principal>) -> Result<content::Plain<String>, Status> {
+    let config = &*CONFIG.lock().unwrap();
+    let patterns = match (&config.known_hosts_pattern, principal) {
+        (Some(pattern), _) => vec![pattern.clone()],
+        (None, Some(principal)) => {
+            let destinations: Vec<&Destination> = principal
+                .groups(roles::Role::Submitter)
+                .into_iter()
+                .flat_map(|group| {
+                    if group == roles::GROUPS[0] {
+                        config.admin_destinations.iter()
+                    } else {
+                        config.user_destinations.iter()
+                    }
+                })
+                .collect();
+            ca::host_patterns(&destinations)
+        }
+        (None, None) => return Err(Status::Unauthorized),
+    };
+    ca::known_hosts(&patterns)
+        .map(content::Plain)
+        .map_err(|_| Status::NotFound)
+}
+
+/// The user CA keys the group's hosts trust, public like the keys themselves.
+#[get("/ca/<file>")]
+fn ca_get(file: String) -> Result<content::Plain<String>, Status> {
+    match file.strip_suffix(".pub") {
+        Some(group) if roles::GROUPS.contains(&group) => {
+            ca::trusted_keys(group == roles::GROUPS[0])
+                .map(content::Plain)
+                .map_err(|e| {
+                    error!("Could not load the CA keys: {:?}", e);
+                    Status::InternalServerError
+                })
+        }
+        _ => Err(Status::NotFound),
+    }
+}
+
 #[get("/favicon.ico")]
 fn favicon() -> io::Result<NamedFile> {
     NamedFile::open("static/favicon.ico")
@@ -282,6 +1423,230 @@ fn main() {
         "Set the pre-shared key to add keys the user group",
         "USER_PSK",
     );
+    opts.optopt(
+        "g",
+        "gitlab-tokens",
+        "Set the API tokens used to resolve GitLab groups (e.g. enolab=TOKEN,tublab=TOKEN)",
+        "GITLAB_TOKENS",
+    );
+    opts.optopt(
+        "i",
+        "gitea-instances",
+        "Set additional Gitea/Forgejo instances (e.g. myforge=https://git.example.org)",
+        "GITEA_INSTANCES",
+    );
+    opts.optopt(
+        "",
+        "create-admin",
+        "Create an admin account (or reset its password) owning all groups with the password read from stdin and exit",
+        "USERNAME",
+    );
+    opts.optopt(
+        "",
+        "delete-admin",
+        "Delete an admin account and exit",
+        "USERNAME",
+    );
+    opts.optopt(
+        "",
+        "oidc-issuer",
+        "Enable OpenID Connect login against this issuer (e.g. https://gitlab.enoflag.de)",
+        "OIDC_ISSUER",
+    );
+    opts.optopt(
+        "",
+        "oidc-client-id",
+        "Set the OpenID Connect client id",
+        "OIDC_CLIENT_ID",
+    );
+    opts.optopt(
+        "",
+        "oidc-client-secret",
+        "Set the OpenID Connect client secret",
+        "OIDC_CLIENT_SECRET",
+    );
+    opts.optopt(
+        "",
+        "oidc-redirect-url",
+        "Set the callback URL registered at the issuer (e.g. https://enokey.example.org/oidc/callback)",
+        "OIDC_REDIRECT_URL",
+    );
+    opts.optopt(
+        "",
+        "oidc-provider",
+        "Set the key provider of the issuer's usernames (default: enolab)",
+        "OIDC_PROVIDER",
+    );
+    opts.optopt(
+        "",
+        "oidc-username-claim",
+        "Set the claim containing the username (default: preferred_username)",
+        "OIDC_USERNAME_CLAIM",
+    );
+    opts.optopt(
+        "",
+        "oidc-groups-claim",
+        "Set the claim containing the user's groups (default: groups)",
+        "OIDC_GROUPS_CLAIM",
+    );
+    opts.optopt(
+        "",
+        "oidc-admin-group",
+        "Grant admin rights to members of this group",
+        "OIDC_ADMIN_GROUP",
+    );
+    opts.optopt(
+        "",
+        "grant-role",
+        "Assign a role (submitter, reviewer, deployer or owner) in a group and exit",
+        "PRINCIPAL:GROUP:ROLE",
+    );
+    opts.optopt(
+        "",
+        "revoke-role",
+        "Remove the role of a principal in a group and exit",
+        "PRINCIPAL:GROUP",
+    );
+    opts.optopt(
+        "",
+        "create-api-token",
+        "Create a token for the JSON API acting as the principal, print it and exit",
+        "PRINCIPAL",
+    );
+    opts.optopt(
+        "",
+        "revoke-api-token",
+        "Revoke all API tokens of the principal and exit",
+        "PRINCIPAL",
+    );
+    opts.optopt(
+        "",
+        "create-host-token",
+        "Create a token for a host of the group to run the agent with, print it and exit",
+        "HOST:GROUP[:USER,...]",
+    );
+    opts.optopt(
+        "",
+        "revoke-host-token",
+        "Revoke the token of the host and exit",
+        "HOST",
+    );
+    opts.optflag("", "dry-run", "Only show what the deploy command would do");
+    opts.optflag(
+        "",
+        "cache-only",
+        "Only use cached provider keys in the generate command",
+    );
+    opts.optopt(
+        "",
+        "interval",
+        "Seconds between the agent's updates, 0 to update once (default 300)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "host-token-file",
+        "Read the agent's host token from the file instead of ENOKEY_HOST_TOKEN",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "cache-dir",
+        "Where authorized-keys-command keeps the last keys (default /var/cache/enokey)",
+        "DIR",
+    );
+    opts.optflag(
+        "",
+        "sign-audit",
+        "Sign every audit record with the deploy key",
+    );
+    opts.optflag(
+        "",
+        "verify-audit",
+        "Check the hash chain and signatures of the audit log and exit",
+    );
+    opts.optflag(
+        "",
+        "require-approval",
+        "Keep submissions pending until a reviewer of the group approves them",
+    );
+    opts.optopt(
+        "",
+        "cert-validity",
+        "Set the longest validity of user certificates in hours by group (e.g. admin=1,user=12, default: 8)",
+        "CERT_VALIDITY",
+    );
+    opts.optopt(
+        "",
+        "rate-limit",
+        "Set the allowed POST requests per minute and IP (default: 30)",
+        "RATE_LIMIT",
+    );
+    opts.optopt(
+        "",
+        "max-failures",
+        "Lock out an IP for 15 minutes after this many wrong authkeys or passwords (default: 5)",
+        "MAX_FAILURES",
+    );
+    opts.optopt(
+        "",
+        "log-level",
+        "Set the log level: error, warn, info, debug or trace (default: info)",
+        "LOG_LEVEL",
+    );
+    opts.optflag("", "log-json", "Log one JSON object per line");
+    opts.optopt(
+        "",
+        "metrics-token",
+        "Serve /metrics to scrapers sending this bearer token",
+        "METRICS_TOKEN",
+    );
+    opts.optopt(
+        "",
+        "known-hosts-pattern",
+        "Publish /ca/known_hosts for this host pattern instead of the destinations, e.g. *.ctf.example",
+        "KNOWN_HOSTS_PATTERN",
+    );
+    opts.optflag(
+        "",
+        "verify-providers",
+        "Require submitters to prove control of a provider account by signing a challenge",
+    );
+    opts.optflag(
+        "",
+        "verify-raw-keys",
+        "Require submitters of raw public keys to prove possession of the private key",
+    );
+    opts.optopt(
+        "",
+        "ldap-url",
+        "Set the LDAP server used as key source (e.g. ldaps://ldap.example.org)",
+        "LDAP_URL",
+    );
+    opts.optopt(
+        "",
+        "ldap-base-dn",
+        "Set the base DN for LDAP searches",
+        "LDAP_BASE_DN",
+    );
+    opts.optopt(
+        "",
+        "ldap-bind-dn",
+        "Set the DN to bind as (anonymous bind if not set)",
+        "LDAP_BIND_DN",
+    );
+    opts.optopt(
+        "",
+        "ldap-bind-password",
+        "Set the password for the LDAP bind DN",
+        "LDAP_BIND_PASSWORD",
+    );
+    opts.optopt(
+        "",
+        "ldap-filter",
+        "Set an additional filter for LDAP user entries (e.g. (objectClass=ldapPublicKey))",
+        "LDAP_FILTER",
+    );
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -294,6 +1659,148 @@ fn main() {
         print_usage(&program, &opts);
         return;
     }
+    let command = matches.free.first().map_or("serve", |c| c.as_str());
+    let level = match matches.opt_str("log-level").filter(|l| !l.is_empty()) {
+        Some(level) => match level.parse::<LevelFilter>() {
+            Ok(level) => level,
+            Err(e) => {
+                println!("Could not parse log level: {:?}", e);
+                return;
+            }
+        },
+        // keep the output of commands readable
+        None if command == "serve" || command == "agent" => LevelFilter::INFO,
+        None => LevelFilter::WARN,
+    };
+    // sshd reads the keys from stdout
+    logging::init(
+        level,
+        matches.opt_present("log-json"),
+        command == "authorized-keys-command",
+    );
+    if let Some(username) = matches.opt_str("create-admin") {
+        let mut password = String::new();
+        if let Err(e) = io::stdin().read_line(&mut password) {
+            println!("Could not read password: {:?}", e);
+            return;
+        }
+        match auth::create_account(&username, password.trim_end_matches(&['\r', '\n'][..]))
+            .and_then(|_| {
+                roles::GROUPS
+                    .iter()
+                    .try_for_each(|g| roles::grant(&username, g, roles::Role::Owner))
+            }) {
+            Ok(_) => println!("Created admin account {}", &username),
+            Err(e) => println!("Could not create admin account: {:?}", e),
+        }
+        return;
+    }
+    if let Some(username) = matches.opt_str("delete-admin") {
+        match auth::delete_account(&username).and_then(|_| roles::revoke_all(&username)) {
+            Ok(_) => println!("Deleted admin account {}", &username),
+            Err(e) => println!("Could not delete admin account: {:?}", e),
+        }
+        return;
+    }
+    if matches.opt_present("verify-audit") {
+        let key = ssh_key::PrivateKey::read_openssh_file(&storage::deploy_key_path())
+            .map(|k| k.public_key().clone())
+            .ok();
+        if key.is_none() {
+            println!("Warning: Deploy key not found, not checking signatures");
+        }
+        match audit::verify(key.as_ref()) {
+            Ok(count) => println!("Audit log intact ({} records)", count),
+            Err((line, reason)) => {
+                println!("Audit log broken at line {}: {}", line, reason);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(assignment) = matches.opt_str("grant-role") {
+        let split: Vec<&str> = assignment.rsplitn(3, ':').collect();
+        let result = match split.len() {
+            3 => {
+                roles::Role::parse(split[0]).and_then(|role| roles::grant(split[2], split[1], role))
+            }
+            _ => Err(EnokeysError::InvalidEnvironmentError),
+        };
+        match result {
+            Ok(_) => println!("Granted {}", &assignment),
+            Err(e) => println!("Could not grant role: {:?}", e),
+        }
+        return;
+    }
+    if let Some(assignment) = matches.opt_str("revoke-role") {
+        let split: Vec<&str> = assignment.rsplitn(2, ':').collect();
+        let result = match split.len() {
+            2 => roles::revoke(split[1], split[0]),
+            _ => Err(EnokeysError::InvalidEnvironmentError),
+        };
+        match result {
+            Ok(_) => println!("Revoked {}", &assignment),
+            Err(e) => println!("Could not revoke role: {:?}", e),
+        }
+        return;
+    }
+    if let Some(principal) = matches.opt_str("create-api-token") {
+        match tokens::create(&principal) {
+            Ok(token) => println!("{}", token),
+            Err(e) => println!("Could not create API token: {:?}", e),
+        }
+        return;
+    }
+    if let Some(host) = matches.opt_str("create-host-token") {
+        let mut split = host.splitn(3, ':');
+        let (name, group) = (
+            split.next().unwrap_or_default(),
+            split.next().unwrap_or_default(),
+        );
+        let users: Vec<&str> = split
+            .next()
+            .map_or(vec![], |u| u.split(',').filter(|u| !u.is_empty()).collect());
+        match hosts::create(name, group, &users) {
+            Ok(token) => println!("{}", token),
+            Err(e) => println!("Could not create host token: {:?}", e),
+        }
+        return;
+    }
+    if let Some(host) = matches.opt_str("revoke-host-token") {
+        match hosts::revoke(&host) {
+            Ok(true) => println!("Revoked the token of {}", &host),
+            Ok(false) => println!("Unknown host: {}", &host),
+            Err(e) => println!("Could not revoke host token: {:?}", e),
+        }
+        return;
+    }
+    if let Some(principal) = matches.opt_str("revoke-api-token") {
+        match tokens::revoke(&principal) {
+            Ok(count) => println!("Revoked {} API tokens of {}", count, &principal),
+            Err(e) => println!("Could not revoke API tokens: {:?}", e),
+        }
+        return;
+    }
+
+    let flags = cli::Flags {
+        dry_run: matches.opt_present("dry-run"),
+        cache_only: matches.opt_present("cache-only"),
+        interval: match matches.opt_str("interval").map(|i| i.parse()) {
+            Some(Ok(interval)) => interval,
+            Some(Err(e)) => {
+                println!("Could not parse interval: {:?}", e);
+                process::exit(1);
+            }
+            None => agent::DEFAULT_INTERVAL,
+        },
+        token_file: matches.opt_str("host-token-file"),
+        cache_dir: matches.opt_str("cache-dir"),
+    };
+    // the agent runs on hosts without ENOKEY's configuration and storage
+    if command == "agent" || command == "authorized-keys-command" {
+        exit_on_error(agent::run(command, &matches.free[1..], &flags));
+        return;
+    }
 
     {
         let config = &mut *CONFIG.lock().unwrap();
@@ -301,7 +1808,9 @@ fn main() {
         let admin_env = match matches.opt_str("a") {
             Some(admin) => parse_destinations(&admin),
             None => {
-                println!("Warning: No admin servers set");
+                if command == "serve" {
+                    warn!("No admin servers set");
+                }
                 Ok(vec![])
             }
         };
@@ -309,7 +1818,9 @@ fn main() {
         let user_env = match matches.opt_str("u") {
             Some(user) => parse_destinations(&user),
             None => {
-                println!("Warning: No user servers set");
+                if command == "serve" {
+                    warn!("No user servers set");
+                }
                 Ok(vec![])
             }
         };
@@ -317,7 +1828,7 @@ fn main() {
         config.user_destinations = match user_env {
             Ok(user_env) => user_env.clone(),
             Err(e) => {
-                println!("Could not parse user servers: {:?}", e);
+                error!("Could not parse user servers: {:?}", e);
                 return;
             }
         };
@@ -325,7 +1836,7 @@ fn main() {
         config.admin_destinations = match admin_env {
             Ok(admin_env) => admin_env.clone(),
             Err(e) => {
-                println!("Could not parse admin servers: {:?}", e);
+                error!("Could not parse admin servers: {:?}", e);
                 return;
             }
         };
@@ -333,7 +1844,7 @@ fn main() {
         config
             .admin_destinations
             .extend(config.user_destinations.iter().cloned());
-        println!(
+        info!(
             "admin destinations: {:?}",
             &config
                 .admin_destinations
@@ -341,7 +1852,7 @@ fn main() {
                 .map(|d| d.destination_name.clone())
                 .collect::<String>()
         );
-        println!(
+        info!(
             "user destinations: {:?}",
             &config
                 .user_destinations
@@ -350,26 +1861,211 @@ fn main() {
                 .collect::<String>()
         );
 
-        config.user_psk = matches.opt_str("q").unwrap_or_else(|| {
-            println!("Warning: User PSK not set.");
-            "default".to_string()
-        });
+        config.user_psk = matches
+            .opt_str("q")
+            .filter(|psk| !psk.is_empty())
+            .unwrap_or_else(|| {
+                if command == "serve" {
+                    warn!("User PSK not set");
+                }
+                "default".to_string()
+            });
+
+        config.admin_psk = matches
+            .opt_str("p")
+            .filter(|psk| !psk.is_empty())
+            .unwrap_or_else(|| {
+                if command == "serve" {
+                    warn!("Admin PSK not set");
+                }
+                "default".to_string()
+            });
 
-        config.admin_psk = matches.opt_str("p").unwrap_or_else(|| {
-            println!("Warning: Admin PSK not set.");
-            "default".to_string()
-        });
+        if command == "serve"
+            && Environment::active().map(|e| e.is_prod()).unwrap_or(true)
+            && (config.admin_psk == "default" || config.user_psk == "default")
+        {
+            error!("Refusing to start in production with the default PSK");
+            return;
+        }
 
-        storage::load_deploy_keypair().unwrap();
+        config.gitlab_tokens = match matches.opt_str("g") {
+            Some(tokens) => match parse_tokens(&tokens) {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    error!("Could not parse gitlab tokens: {:?}", e);
+                    return;
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        config.gitea_instances = match matches.opt_str("i") {
+            Some(instances) => match parse_tokens(&instances)
+                .and_then(|i| scraper::check_gitea_instances(&i).map(|_| i))
+            {
+                Ok(instances) => instances,
+                Err(e) => {
+                    error!("Could not parse gitea instances: {:?}", e);
+                    return;
+                }
+            },
+            None => HashMap::new(),
+        };
+
+        config.ldap = match matches.opt_str("ldap-url") {
+            Some(ref url) if !url.is_empty() => Some(ldap::LdapConfig {
+                url: url.to_string(),
+                base_dn: matches.opt_str("ldap-base-dn").unwrap_or_default(),
+                bind_dn: matches.opt_str("ldap-bind-dn").filter(|dn| !dn.is_empty()),
+                bind_password: matches.opt_str("ldap-bind-password").unwrap_or_default(),
+                filter: matches.opt_str("ldap-filter").unwrap_or_default(),
+            }),
+            _ => None,
+        };
+
+        config.oidc = match matches.opt_str("oidc-issuer") {
+            Some(ref issuer) if !issuer.is_empty() => Some(oidc::OidcConfig {
+                issuer: issuer.to_string(),
+                client_id: matches.opt_str("oidc-client-id").unwrap_or_default(),
+                client_secret: matches.opt_str("oidc-client-secret").unwrap_or_default(),
+                redirect_url: matches.opt_str("oidc-redirect-url").unwrap_or_default(),
+                provider: matches
+                    .opt_str("oidc-provider")
+                    .filter(|p| !p.is_empty())
+                    .unwrap_or_else(|| "enolab".to_string()),
+                username_claim: matches
+                    .opt_str("oidc-username-claim")
+                    .filter(|c| !c.is_empty())
+                    .unwrap_or_else(|| "preferred_username".to_string()),
+                groups_claim: matches
+                    .opt_str("oidc-groups-claim")
+                    .filter(|c| !c.is_empty())
+                    .unwrap_or_else(|| "groups".to_string()),
+                admin_group: matches
+                    .opt_str("oidc-admin-group")
+                    .filter(|g| !g.is_empty()),
+            }),
+            _ => None,
+        };
+
+        config.verify_providers = matches.opt_present("verify-providers");
+        config.verify_raw_keys = matches.opt_present("verify-raw-keys");
+        config.require_approval = matches.opt_present("require-approval");
+        config.metrics_token = matches.opt_str("metrics-token").filter(|t| !t.is_empty());
+        config.known_hosts_pattern = matches
+            .opt_str("known-hosts-pattern")
+            .filter(|p| !p.is_empty());
+        if let Some(validity) = matches.opt_str("cert-validity").filter(|v| !v.is_empty()) {
+            config.cert_validity = match parse_validity(&validity) {
+                Ok(validity) => validity,
+                Err(e) => {
+                    error!("Could not parse certificate validity: {:?}", e);
+                    return;
+                }
+            };
+        }
+
+        if let Some(requests) = matches.opt_str("rate-limit").filter(|r| !r.is_empty()) {
+            config.limits.requests = match requests.parse() {
+                Ok(requests) => requests,
+                Err(e) => {
+                    error!("Could not parse rate limit: {:?}", e);
+                    return;
+                }
+            };
+        }
+        if let Some(failures) = matches.opt_str("max-failures").filter(|f| !f.is_empty()) {
+            config.limits.failures = match failures.parse() {
+                Ok(failures) => failures,
+                Err(e) => {
+                    error!("Could not parse max failures: {:?}", e);
+                    return;
+                }
+            };
+        }
+
+        for psk in &[&config.admin_psk, &config.user_psk] {
+            if psk.as_str() != "default" {
+                logging::redact_secret(psk);
+            }
+        }
+        for token in config.gitlab_tokens.values() {
+            logging::redact_secret(token);
+        }
+        if let Some(ref ldap) = config.ldap {
+            logging::redact_secret(&ldap.bind_password);
+        }
+        if let Some(ref oidc) = config.oidc {
+            logging::redact_secret(&oidc.client_secret);
+        }
+        if let Some(ref token) = config.metrics_token {
+            logging::redact_secret(token);
+        }
+
+        // only serving and deploying need the deploy key
+        if ["serve", "deploy", "deploy-ca"].contains(&command) {
+            exit_on_error(storage::load_deploy_keypair());
+        }
+        if matches.opt_present("sign-audit") {
+            exit_on_error(
+                audit::enable_signing(&storage::deploy_key_path()).map_err(|e| {
+                    EnokeysError::InvalidData(format!(
+                        "Could not load the deploy key to sign the audit log: {:?}",
+                        e
+                    ))
+                }),
+            );
+        }
+    }
+
+    if command != "serve" {
+        exit_on_error(cli::run(command, &matches.free[1..], &flags));
+        return;
     }
 
-    rocket::ignite()
+    let server = rocket::ignite()
         .mount("/static", StaticFiles::from("static"))
-        .mount("/keyfiles", StaticFiles::from("keyfiles"))
+        .mount(api::PREFIX, api::routes())
+        .mount("/api", routes![api::openapi_get])
         .mount(
             "/",
-            routes![index_post, index_get, deploy_get, deploy_post, favicon],
+            routes![
+                index_post,
+                index_get,
+                verify_post,
+                deploy_get,
+                deploy_post,
+                keyfile_get,
+                keyfile_link,
+                invitations_get,
+                invitations_post,
+                invitations_revoke,
+                review_get,
+                review_approve,
+                review_reject,
+                roles_get,
+                roles_post,
+                roles_revoke,
+                audit_get,
+                audit_export,
+                login_get,
+                login_post,
+                logout_post,
+                oidc_login,
+                oidc_callback,
+                agent::authorized_keys_get,
+                agent::user_authorized_keys_get,
+                ca_get,
+                known_hosts_get,
+                favicon
+            ],
         )
+        .register(catchers![unauthorized, forbidden, too_many_requests])
+        .attach(logging::RequestSpans)
+        .attach(AdHoc::on_request("CSRF token", |request, _| {
+            csrf::remember(request)
+        }))
         .attach(Template::fairing())
         .attach(AdHoc::on_response("Security Headers", |_, resp| {
             resp.adjoin_raw_header("X-XSS-Protection", "1; mode=block");
@@ -377,8 +2073,25 @@ fn main() {
             resp.adjoin_raw_header("Content-Security-Policy", "default-src 'self'");
             resp.adjoin_raw_header("X-Content-Type-Options", "nosniff"); // Disables content sniffing for older browsers
             resp.adjoin_raw_header("Referrer-Policy", "no-referrer-when-downgrade"); // won't send a referrer when going from https to http
-        }))
-        .launch();
+        }));
+    // the metrics name destinations and providers, they are only served with a token
+    let server = if CONFIG.lock().unwrap().metrics_token.is_some() {
+        server.mount("/", routes![metrics_get])
+    } else {
+        server
+    };
+    server.launch();
+}
+
+/// Prints the error of a command and exits with a non-zero status.
+fn exit_on_error(result: Result<(), EnokeysError>) {
+    if let Err(e) = result {
+        match e {
+            EnokeysError::InvalidData(message) => println!("{}", message),
+            e => println!("{:?}", e),
+        }
+        process::exit(1);
+    }
 }
 
 fn parse_destinations(input: &str) -> Result<Vec<Destination>, EnokeysError> {
@@ -388,6 +2101,16 @@ fn parse_destinations(input: &str) -> Result<Vec<Destination>, EnokeysError> {
     let entries: Vec<&str> = input.split(",").collect();
     let mut destinations = vec![];
     for entry in entries {
+        if let Some(kind) = deploy::parse_kind(entry)? {
+            destinations.push(Destination {
+                address: String::new(),
+                userauth_agent: String::new(),
+                destination_name: entry.to_string(),
+                port: 0,
+                kind,
+            });
+            continue;
+        }
         let split: Vec<&str> = entry.split('@').collect();
         let (userauth_agent, address) = match split.len() {
             2 => (split[0], split[1]),
@@ -400,11 +2123,38 @@ fn parse_destinations(input: &str) -> Result<Vec<Destination>, EnokeysError> {
             userauth_agent: userauth_agent.to_string(),
             destination_name: format!("{}@{}:{}", &userauth_agent, &address, port),
             port: port,
+            kind: deploy::Kind::Ssh,
         })
     }
     Ok(destinations)
 }
 
+fn parse_tokens(input: &str) -> Result<HashMap<String, String>, EnokeysError> {
+    let mut tokens = HashMap::new();
+    for entry in input.split(',').filter(|e| !e.is_empty()) {
+        let split: Vec<&str> = entry.splitn(2, '=').collect();
+        match split.len() {
+            2 => tokens.insert(split[0].to_string(), split[1].to_string()),
+            _ => return Err(EnokeysError::InvalidEnvironmentError),
+        };
+    }
+    Ok(tokens)
+}
+
+fn parse_validity(input: &str) -> Result<HashMap<String, u64>, EnokeysError> {
+    let mut validity = HashMap::new();
+    for (group, hours) in parse_tokens(input)? {
+        if !roles::GROUPS.contains(&group.as_str()) {
+            return Err(EnokeysError::InvalidData(format!(
+                "unknown group: {}",
+                group
+            )));
+        }
+        validity.insert(group, hours.parse()?);
+    }
+    Ok(validity)
+}
+
 fn parse_port(address: &str) -> Result<u16, EnokeysError> {
     let split = address.split(":").collect::<Vec<&str>>();
     if split.len() == 1 {
@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+
+use auth;
+use CONFIG;
+
+const FETCH_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const DEPLOY_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if seconds <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Default)]
+struct Metrics {
+    /// By group and provider.
+    submissions: BTreeMap<(String, String), u64>,
+    /// By provider.
+    fetch_duration: BTreeMap<String, Histogram>,
+    fetch_failures: BTreeMap<String, u64>,
+    cache_hits: u64,
+    cache_misses: u64,
+    /// By authorized_keys file.
+    authorized_keys: BTreeMap<String, u64>,
+    /// By destination and result.
+    deploys: BTreeMap<(String, &'static str), u64>,
+    deploy_duration: BTreeMap<String, Histogram>,
+}
+
+/// Escapes a label value as required by the Prometheus text format.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn result(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+pub fn submission(group: &str, provider: &str) {
+    let mut metrics = METRICS.lock().unwrap();
+    *metrics
+        .submissions
+        .entry((group.to_string(), provider.to_string()))
+        .or_insert(0) += 1;
+}
+
+/// Records a request to a provider that was not answered from the cache.
+pub fn fetch(provider: &str, duration: Duration, success: bool) {
+    let mut metrics = METRICS.lock().unwrap();
+    metrics
+        .fetch_duration
+        .entry(provider.to_string())
+        .or_insert_with(|| Histogram::new(FETCH_BUCKETS))
+        .observe(duration);
+    if !success {
+        *metrics
+            .fetch_failures
+            .entry(provider.to_string())
+            .or_insert(0) += 1;
+    }
+}
+
+pub fn cache_lookup(hit: bool) {
+    let mut metrics = METRICS.lock().unwrap();
+    if hit {
+        metrics.cache_hits += 1;
+    } else {
+        metrics.cache_misses += 1;
+    }
+}
+
+pub fn authorized_keys(file: &str, keys: u64) {
+    METRICS
+        .lock()
+        .unwrap()
+        .authorized_keys
+        .insert(file.to_string(), keys);
+}
+
+pub fn deploy(destination: &str, duration: Duration, success: bool) {
+    let mut metrics = METRICS.lock().unwrap();
+    *metrics
+        .deploys
+        .entry((destination.to_string(), result(success)))
+        .or_insert(0) += 1;
+    metrics
+        .deploy_duration
+        .entry(destination.to_string())
+        .or_insert_with(|| Histogram::new(DEPLOY_BUCKETS))
+        .observe(duration);
+}
+
+/// All metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut out = String::new();
+
+    out.push_str("# HELP enokey_submissions_total Accepted key submissions.\n");
+    out.push_str("# TYPE enokey_submissions_total counter\n");
+    for ((group, provider), count) in &metrics.submissions {
+        let _ = writeln!(
+            out,
+            "enokey_submissions_total{{group=\"{}\",provider=\"{}\"}} {}",
+            label(group),
+            label(provider),
+            count
+        );
+    }
+
+    out.push_str(
+        "# HELP enokey_fetch_duration_seconds Time spent fetching keys from a provider.\n",
+    );
+    out.push_str("# TYPE enokey_fetch_duration_seconds histogram\n");
+    for (provider, histogram) in &metrics.fetch_duration {
+        histogram.render(
+            &mut out,
+            "enokey_fetch_duration_seconds",
+            &format!("provider=\"{}\"", label(provider)),
+        );
+    }
+
+    out.push_str("# HELP enokey_fetch_failures_total Failed fetches from a provider.\n");
+    out.push_str("# TYPE enokey_fetch_failures_total counter\n");
+    for (provider, count) in &metrics.fetch_failures {
+        let _ = writeln!(
+            out,
+            "enokey_fetch_failures_total{{provider=\"{}\"}} {}",
+            label(provider),
+            count
+        );
+    }
+
+    out.push_str("# HELP enokey_cache_lookups_total Lookups in the provider key cache.\n");
+    out.push_str("# TYPE enokey_cache_lookups_total counter\n");
+    let _ = writeln!(
+        out,
+        "enokey_cache_lookups_total{{result=\"hit\"}} {}",
+        metrics.cache_hits
+    );
+    let _ = writeln!(
+        out,
+        "enokey_cache_lookups_total{{result=\"miss\"}} {}",
+        metrics.cache_misses
+    );
+    out.push_str("# HELP enokey_cache_hit_ratio Share of cache lookups that were hits.\n");
+    out.push_str("# TYPE enokey_cache_hit_ratio gauge\n");
+    let lookups = metrics.cache_hits + metrics.cache_misses;
+    let ratio = if lookups == 0 {
+        0.0
+    } else {
+        metrics.cache_hits as f64 / lookups as f64
+    };
+    let _ = writeln!(out, "enokey_cache_hit_ratio {}", ratio);
+
+    out.push_str(
+        "# HELP enokey_authorized_keys Keys in the last generated authorized_keys file.\n",
+    );
+    out.push_str("# TYPE enokey_authorized_keys gauge\n");
+    for (file, keys) in &metrics.authorized_keys {
+        let _ = writeln!(
+            out,
+            "enokey_authorized_keys{{file=\"{}\"}} {}",
+            label(file),
+            keys
+        );
+    }
+
+    out.push_str("# HELP enokey_deploys_total Deployments to a destination.\n");
+    out.push_str("# TYPE enokey_deploys_total counter\n");
+    for ((destination, result), count) in &metrics.deploys {
+        let _ = writeln!(
+            out,
+            "enokey_deploys_total{{destination=\"{}\",result=\"{}\"}} {}",
+            label(destination),
+            result,
+            count
+        );
+    }
+
+    out.push_str("# HELP enokey_deploy_duration_seconds Time spent deploying to a destination.\n");
+    out.push_str("# TYPE enokey_deploy_duration_seconds histogram\n");
+    for (destination, histogram) in &metrics.deploy_duration {
+        histogram.render(
+            &mut out,
+            "enokey_deploy_duration_seconds",
+            &format!("destination=\"{}\"", label(destination)),
+        );
+    }
+    out
+}
+
+/// Requires `Authorization: Bearer <token>` with the configured metrics token.
+pub struct MetricsAuth;
+
+impl<'a, 'r> FromRequest<'a, 'r> for MetricsAuth {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<MetricsAuth, ()> {
+        let expected = match CONFIG.lock().unwrap().metrics_token {
+            Some(ref token) => token.clone(),
+            None => return Outcome::Failure((Status::Forbidden, ())),
+        };
+        match request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+        {
+            Some(token) if auth::constant_time_eq(&expected, token) => {
+                Outcome::Success(MetricsAuth)
+            }
+            // not Unauthorized, the catcher would redirect scrapers to the login page
+            _ => Outcome::Failure((Status::Forbidden, ())),
+        }
+    }
+}
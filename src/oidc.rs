@@ -0,0 +1,532 @@
+use chrono::Utc;
+use rocket::http::{Cookie, Cookies, SameSite};
+use rocket::outcome::IntoOutcome;
+use rocket::request::{self, FromRequest, Request};
+use rsa::sha2::{Digest, Sha256};
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+use serde_json::Value;
+
+use auth;
+use challenge;
+use error::EnokeysError;
+
+const SESSION_COOKIE: &str = "enokey_oidc";
+const STATE_COOKIE: &str = "enokey_oidc_state";
+/// Seconds an ID token is still accepted after it expired, for clock skew.
+const LEEWAY: i64 = 60;
+
+#[derive(Clone, Debug)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_url: String,
+    /// The ENOKEY key provider the issuer's usernames belong to (e.g. `enolab`).
+    pub provider: String,
+    pub username_claim: String,
+    pub groups_claim: String,
+    pub admin_group: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// An RSA key of the issuer's JWK set, other key types are ignored.
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: String,
+    kid: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct IdClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+}
+
+/// A submitter logged in via the configured OpenID Connect issuer.
+#[derive(Serialize, Deserialize)]
+pub struct OidcUser {
+    pub username: String,
+    pub admin: bool,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for OidcUser {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<OidcUser, ()> {
+        request
+            .cookies()
+            .get_private(SESSION_COOKIE)
+            .and_then(|cookie| serde_json::from_str(cookie.value()).ok())
+            .or_forward(())
+    }
+}
+
+fn get_json<T>(url: &str) -> Result<T, EnokeysError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let mut res = reqwest::get(url)?;
+    if res.status() != 200 {
+        return Err(EnokeysError::InvalidProviderResponse);
+    }
+    Ok(res.json()?)
+}
+
+fn same_issuer(a: &str, b: &str) -> bool {
+    a.trim_end_matches('/') == b.trim_end_matches('/')
+}
+
+fn discover(config: &OidcConfig) -> Result<Discovery, EnokeysError> {
+    let discovery: Discovery = get_json(&format!(
+        "{}/.well-known/openid-configuration",
+        config.issuer.trim_end_matches('/')
+    ))?;
+    if !same_issuer(&discovery.issuer, &config.issuer) {
+        return Err(EnokeysError::InvalidData(format!(
+            "discovery is for issuer {}",
+            &discovery.issuer
+        )));
+    }
+    Ok(discovery)
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, EnokeysError> {
+    base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| EnokeysError::InvalidData("invalid id_token encoding".to_string()))
+}
+
+/// Checks the RS256 signature of the ID token with the issuer's keys and its claims,
+/// returns the subject.
+fn validate_id_token(
+    config: &OidcConfig,
+    jwks: &Jwks,
+    id_token: &str,
+    nonce: &str,
+    now: i64,
+) -> Result<String, EnokeysError> {
+    let parts: Vec<&str> = id_token.split('.').collect();
+    if parts.len() != 3 {
+        return Err(EnokeysError::InvalidData("invalid id_token".to_string()));
+    }
+    let header: JwtHeader = serde_json::from_slice(&decode(parts[0])?)?;
+    if header.alg != "RS256" {
+        return Err(EnokeysError::InvalidData(format!(
+            "unsupported id_token algorithm {}",
+            &header.alg
+        )));
+    }
+    let digest = Sha256::digest(format!("{}.{}", parts[0], parts[1]).as_bytes());
+    let signature = decode(parts[2])?;
+    let verified = jwks
+        .keys
+        .iter()
+        .filter(|key| key.kty == "RSA" && (header.kid.is_none() || key.kid == header.kid))
+        .filter_map(|key| match (&key.n, &key.e) {
+            (Some(n), Some(e)) => Some((decode(n).ok()?, decode(e).ok()?)),
+            _ => None,
+        })
+        .filter_map(|(n, e)| {
+            RsaPublicKey::new(BigUint::from_bytes_be(&n), BigUint::from_bytes_be(&e)).ok()
+        })
+        .any(|key| {
+            key.verify(Pkcs1v15Sign::new::<Sha256>(), &digest, &signature)
+                .is_ok()
+        });
+    if !verified {
+        return Err(EnokeysError::InvalidSignature);
+    }
+
+    let claims: IdClaims = serde_json::from_slice(&decode(parts[1])?)?;
+    let audience = match claims.aud {
+        Audience::One(ref aud) => aud == &config.client_id,
+        Audience::Many(ref aud) => aud.contains(&config.client_id),
+    };
+    if !same_issuer(&claims.iss, &config.issuer)
+        || !audience
+        || claims.exp + LEEWAY < now
+        || claims.nonce.as_deref() != Some(nonce)
+    {
+        return Err(EnokeysError::InvalidCredentials);
+    }
+    Ok(claims.sub)
+}
+
+/// The nonce remembered with the state, if the callback's `state` is the expected one.
+fn check_state(cookie: Option<&str>, state: &str) -> Result<String, EnokeysError> {
+    let (expected, nonce): (String, String) = cookie
+        .and_then(|cookie| serde_json::from_str(cookie).ok())
+        .ok_or(EnokeysError::InvalidCredentials)?;
+    if !auth::constant_time_eq(&expected, state) {
+        return Err(EnokeysError::InvalidCredentials);
+    }
+    Ok(nonce)
+}
+
+/// Returns the issuer's authorization URL and remembers the `state` and `nonce` in a cookie.
+pub fn authorization_url(
+    config: &OidcConfig,
+    cookies: &mut Cookies,
+) -> Result<String, EnokeysError> {
+    let discovery = discover(config)?;
+    let state = challenge::generate_nonce();
+    let nonce = challenge::generate_nonce();
+    // the callback is a cross-site navigation, a strict cookie would not be sent
+    cookies.add_private(
+        Cookie::build(STATE_COOKIE, serde_json::to_string(&(&state, &nonce))?)
+            .same_site(SameSite::Lax)
+            .finish(),
+    );
+    let url = reqwest::Url::parse_with_params(
+        &discovery.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &config.client_id),
+            ("redirect_uri", &config.redirect_url),
+            ("scope", "openid profile"),
+            ("state", &state),
+            ("nonce", &nonce),
+        ],
+    )
+    .map_err(|e| EnokeysError::InvalidData(e.to_string()))?;
+    Ok(url.into_string())
+}
+
+/// Exchanges the authorization code and stores the resulting identity in the session.
+pub fn callback(
+    config: &OidcConfig,
+    code: &str,
+    state: &str,
+    cookies: &mut Cookies,
+) -> Result<OidcUser, EnokeysError> {
+    let expected = cookies
+        .get_private(STATE_COOKIE)
+        .map(|cookie| cookie.value().to_string());
+    cookies.remove_private(Cookie::named(STATE_COOKIE));
+    let nonce = check_state(expected.as_deref(), state)?;
+    let user = exchange(config, code, &nonce, Utc::now().timestamp())?;
+    cookies.add_private(Cookie::new(SESSION_COOKIE, serde_json::to_string(&user)?));
+    Ok(user)
+}
+
+/// Redeems the code at the issuer, validates the ID token and reads the claims of its
+/// subject from the userinfo endpoint.
+fn exchange(
+    config: &OidcConfig,
+    code: &str,
+    nonce: &str,
+    now: i64,
+) -> Result<OidcUser, EnokeysError> {
+    let discovery = discover(config)?;
+    let client = reqwest::Client::new();
+    let mut res = client
+        .post(&discovery.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &config.redirect_url),
+            ("client_id", &config.client_id),
+            ("client_secret", &config.client_secret),
+        ])
+        .send()?;
+    if res.status() != 200 {
+        return Err(EnokeysError::InvalidCredentials);
+    }
+    let token: TokenResponse = res.json()?;
+    let subject = validate_id_token(
+        config,
+        &get_json(&discovery.jwks_uri)?,
+        &token.id_token,
+        nonce,
+        now,
+    )?;
+
+    let mut res = client
+        .get(&discovery.userinfo_endpoint)
+        .bearer_auth(&token.access_token)
+        .send()?;
+    if res.status() != 200 {
+        return Err(EnokeysError::InvalidProviderResponse);
+    }
+    let claims: Value = res.json()?;
+    if claims.get("sub").and_then(|s| s.as_str()) != Some(subject.as_str()) {
+        return Err(EnokeysError::InvalidCredentials);
+    }
+    let username = match claims.get(&config.username_claim).and_then(|u| u.as_str()) {
+        Some(username) if !username.is_empty() => username.to_string(),
+        _ => {
+            return Err(EnokeysError::InvalidData(format!(
+                "claim {} missing",
+                &config.username_claim
+            )))
+        }
+    };
+    let admin = match (&config.admin_group, claims.get(&config.groups_claim)) {
+        (Some(admin_group), Some(Value::Array(groups))) => groups
+            .iter()
+            .any(|g| g.as_str() == Some(admin_group.as_str())),
+        (Some(admin_group), Some(Value::String(group))) => group == admin_group,
+        _ => false,
+    };
+
+    Ok(OidcUser { username, admin })
+}
+
+pub fn logout(cookies: &mut Cookies) {
+    cookies.remove_private(Cookie::named(SESSION_COOKIE));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use rand::rngs::OsRng;
+    use rsa::traits::PublicKeyParts;
+    use rsa::RsaPrivateKey;
+    use serde_json::json;
+
+    use super::*;
+
+    const CLIENT_ID: &str = "enokey";
+    const NOW: i64 = 1_700_000_000;
+    const NONCE: &str = "nonce";
+
+    lazy_static! {
+        static ref KEY: RsaPrivateKey = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+        static ref OTHER_KEY: RsaPrivateKey = RsaPrivateKey::new(&mut OsRng, 1024).unwrap();
+    }
+
+    fn encode(data: &[u8]) -> String {
+        base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+    }
+
+    fn sign(claims: &Value, key: &RsaPrivateKey) -> String {
+        let input = format!(
+            "{}.{}",
+            encode(json!({"alg": "RS256", "kid": "1"}).to_string().as_bytes()),
+            encode(claims.to_string().as_bytes())
+        );
+        let signature = key
+            .sign(
+                Pkcs1v15Sign::new::<Sha256>(),
+                &Sha256::digest(input.as_bytes()),
+            )
+            .unwrap();
+        format!("{}.{}", input, encode(&signature))
+    }
+
+    /// Serves discovery, the JWK set of `KEY`, the token and the userinfo endpoint on
+    /// a local port. `id_token` gets the issuer's URL and returns the ID token to issue.
+    fn mock_issuer<F>(id_token: F) -> OidcConfig
+    where
+        F: FnOnce(&str) -> String,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let id_token = id_token(&issuer);
+        let discovery = json!({
+            "issuer": &issuer,
+            "authorization_endpoint": format!("{}/authorize", &issuer),
+            "token_endpoint": format!("{}/token", &issuer),
+            "userinfo_endpoint": format!("{}/userinfo", &issuer),
+            "jwks_uri": format!("{}/jwks", &issuer),
+        });
+        let jwks = json!({"keys": [{
+            "kty": "RSA",
+            "kid": "1",
+            "n": encode(&KEY.n().to_bytes_be()),
+            "e": encode(&KEY.e().to_bytes_be()),
+        }]});
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let response = match path {
+                    "/.well-known/openid-configuration" => discovery.clone(),
+                    "/jwks" => jwks.clone(),
+                    "/token" if String::from_utf8_lossy(&body).contains("code=good") => {
+                        json!({"access_token": "access", "id_token": &id_token})
+                    }
+                    "/userinfo" => json!({
+                        "sub": "1",
+                        "preferred_username": "alice",
+                        "groups": ["enoflag/admins"],
+                    }),
+                    _ => Value::Null,
+                };
+                let (status, body) = match response {
+                    Value::Null => ("400 Bad Request", String::new()),
+                    response => ("200 OK", response.to_string()),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        OidcConfig {
+            issuer,
+            client_id: CLIENT_ID.to_string(),
+            client_secret: "secret".to_string(),
+            redirect_url: "http://enokey/oidc/callback".to_string(),
+            provider: "enolab".to_string(),
+            username_claim: "preferred_username".to_string(),
+            groups_claim: "groups".to_string(),
+            admin_group: Some("enoflag/admins".to_string()),
+        }
+    }
+
+    fn claims(issuer: &str) -> Value {
+        json!({
+            "iss": issuer,
+            "sub": "1",
+            "aud": CLIENT_ID,
+            "exp": NOW + 300,
+            "iat": NOW,
+            "nonce": NONCE,
+        })
+    }
+
+    /// Logs in at an issuer that issues `claims` changed by `change`, signed with `key`.
+    fn login<F>(key: &'static RsaPrivateKey, change: F) -> Result<OidcUser, EnokeysError>
+    where
+        F: FnOnce(&mut Value),
+    {
+        let config = mock_issuer(|issuer| {
+            let mut claims = claims(issuer);
+            change(&mut claims);
+            sign(&claims, key)
+        });
+        exchange(&config, "good", NONCE, NOW)
+    }
+
+    #[test]
+    fn good_token() {
+        let user = login(&KEY, |_| {}).unwrap();
+        assert_eq!(user.username, "alice");
+        assert!(user.admin);
+    }
+
+    #[test]
+    fn audience_list() {
+        let user = login(&KEY, |claims| claims["aud"] = json!(["other", CLIENT_ID]));
+        assert!(user.is_ok());
+    }
+
+    #[test]
+    fn bad_signature() {
+        match login(&OTHER_KEY, |_| {}) {
+            Err(EnokeysError::InvalidSignature) => {}
+            other => panic!("accepted: {:?}", other.map(|u| u.username)),
+        }
+    }
+
+    fn rejected<F>(change: F)
+    where
+        F: FnOnce(&mut Value),
+    {
+        match login(&KEY, change) {
+            Err(EnokeysError::InvalidCredentials) => {}
+            other => panic!("accepted: {:?}", other.map(|u| u.username)),
+        }
+    }
+
+    #[test]
+    fn wrong_audience() {
+        rejected(|claims| claims["aud"] = json!("other"));
+    }
+
+    #[test]
+    fn wrong_issuer() {
+        rejected(|claims| claims["iss"] = json!("https://evil.example.org"));
+    }
+
+    #[test]
+    fn expired() {
+        rejected(|claims| claims["exp"] = json!(NOW - LEEWAY - 1));
+    }
+
+    #[test]
+    fn wrong_nonce() {
+        rejected(|claims| claims["nonce"] = json!("other"));
+    }
+
+    #[test]
+    fn other_subject() {
+        rejected(|claims| claims["sub"] = json!("2"));
+    }
+
+    #[test]
+    fn bad_code() {
+        let config = mock_issuer(|issuer| sign(&claims(issuer), &KEY));
+        assert!(exchange(&config, "bad", NONCE, NOW).is_err());
+    }
+
+    #[test]
+    fn state_mismatch() {
+        let cookie = serde_json::to_string(&("state", NONCE)).unwrap();
+        assert_eq!(check_state(Some(&cookie), "state").unwrap(), NONCE);
+        assert!(check_state(Some(&cookie), "other").is_err());
+        assert!(check_state(None, "state").is_err());
+        assert!(check_state(Some("state"), "state").is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+
+use CONFIG;
+
+const WINDOW: Duration = Duration::from_secs(60);
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+lazy_static! {
+    static ref CLIENTS: Mutex<HashMap<IpAddr, Client>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Default)]
+struct Client {
+    requests: Vec<Instant>,
+    failures: Vec<Instant>,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Requests per minute and IP on guarded routes.
+    pub requests: usize,
+    /// Wrong authkeys or passwords per lockout period before the IP is locked out.
+    pub failures: usize,
+}
+
+/// Counts the request against the client's rate limit, failing with
+/// `429 Too Many Requests` if it is exceeded or the client is locked out.
+pub struct RateLimit {
+    ip: Option<IpAddr>,
+    limits: Limits,
+}
+
+impl RateLimit {
+    /// Records a wrong authkey or password, locking the client out after too many.
+    pub fn failure(&self) {
+        let ip = match self.ip {
+            Some(ip) => ip,
+            None => return,
+        };
+        let mut clients = CLIENTS.lock().unwrap();
+        let client = clients.entry(ip).or_default();
+        client.failures.retain(|f| f.elapsed() < LOCKOUT);
+        client.failures.push(Instant::now());
+        if client.failures.len() >= self.limits.failures {
+            warn!(%ip, failures = client.failures.len(), "locking out client");
+            client.failures.clear();
+            client.locked_until = Some(Instant::now() + LOCKOUT);
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for RateLimit {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<RateLimit, ()> {
+        let limits = CONFIG.lock().unwrap().limits;
+        let ip = match request.client_ip() {
+            Some(ip) => ip,
+            None => return Outcome::Success(RateLimit { ip: None, limits }),
+        };
+        let mut clients = CLIENTS.lock().unwrap();
+        // forget idle clients so the map does not grow without bound
+        clients.retain(|_, c| {
+            c.requests.iter().any(|r| r.elapsed() < WINDOW)
+                || c.failures.iter().any(|f| f.elapsed() < LOCKOUT)
+                || c.locked_until.is_some_and(|l| l > Instant::now())
+        });
+        let client = clients.entry(ip).or_default();
+        if client.locked_until.is_some_and(|l| l > Instant::now()) {
+            return Outcome::Failure((Status::TooManyRequests, ()));
+        }
+        client.requests.retain(|r| r.elapsed() < WINDOW);
+        if client.requests.len() >= limits.requests {
+            return Outcome::Failure((Status::TooManyRequests, ()));
+        }
+        client.requests.push(Instant::now());
+        Outcome::Success(RateLimit {
+            ip: Some(ip),
+            limits,
+        })
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+
+use auth;
+use error::EnokeysError;
+use oidc::OidcUser;
+use ROLES_STORAGE;
+
+pub const GROUPS: [&str; 2] = ["admin", "user"];
+/// Prefix of principals logged in via OpenID Connect, keeps them apart from local accounts.
+pub const OIDC_PREFIX: &str = "oidc:";
+
+lazy_static! {
+    static ref ROLES_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Each role includes the permissions of the roles before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum Role {
+    /// May submit keys to the group.
+    Submitter,
+    /// May view the group's keys, approve pending submissions and invite submitters.
+    Reviewer,
+    /// May deploy the group's authorized_keys to its destinations.
+    Deployer,
+    /// May assign roles for the group.
+    Owner,
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Result<Role, EnokeysError> {
+        match role {
+            "submitter" => Ok(Role::Submitter),
+            "reviewer" => Ok(Role::Reviewer),
+            "deployer" => Ok(Role::Deployer),
+            "owner" => Ok(Role::Owner),
+            _ => Err(EnokeysError::InvalidData(format!("unknown role: {}", role))),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Role::Submitter => "submitter",
+            Role::Reviewer => "reviewer",
+            Role::Deployer => "deployer",
+            Role::Owner => "owner",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub fn group_name(admin: bool) -> &'static str {
+    if admin {
+        GROUPS[0]
+    } else {
+        GROUPS[1]
+    }
+}
+
+fn check_group(group: &str) -> Result<(), EnokeysError> {
+    if GROUPS.contains(&group) {
+        Ok(())
+    } else {
+        Err(EnokeysError::InvalidData(format!(
+            "unknown group: {}",
+            group
+        )))
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Assignment {
+    pub principal: String,
+    pub group: String,
+    pub role: String,
+}
+
+fn load() -> Result<Vec<Assignment>, EnokeysError> {
+    let mut content = String::new();
+    if let Ok(mut file) = File::open(&*ROLES_STORAGE) {
+        file.read_to_string(&mut content)?;
+    }
+    Ok(content
+        .split('\n')
+        .filter_map(|line| {
+            // principals of OpenID Connect users contain a colon themselves
+            let mut split = line.rsplitn(3, ':');
+            match (split.next(), split.next(), split.next()) {
+                (Some(role), Some(group), Some(principal)) => Some(Assignment {
+                    principal: principal.to_string(),
+                    group: group.to_string(),
+                    role: role.to_string(),
+                }),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+fn save(assignments: &[Assignment]) -> Result<(), EnokeysError> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&*ROLES_STORAGE)?;
+    for assignment in assignments {
+        writeln!(
+            file,
+            "{}:{}:{}",
+            &assignment.principal, &assignment.group, &assignment.role
+        )?;
+    }
+    Ok(())
+}
+
+pub fn list() -> Result<Vec<Assignment>, EnokeysError> {
+    let _lock = ROLES_LOCK.lock().unwrap();
+    load()
+}
+
+/// Assigns the role, replacing any previous role of the principal in the group.
+pub fn grant(principal: &str, group: &str, role: Role) -> Result<(), EnokeysError> {
+    check_group(group)?;
+    if principal.is_empty() || principal.contains('\n') {
+        return Err(EnokeysError::InvalidData("invalid principal".to_string()));
+    }
+    let _lock = ROLES_LOCK.lock().unwrap();
+    let mut assignments = load()?;
+    assignments.retain(|a| !(a.principal == principal && a.group == group));
+    assignments.push(Assignment {
+        principal: principal.to_string(),
+        group: group.to_string(),
+        role: role.to_string(),
+    });
+    save(&assignments)
+}
+
+pub fn revoke(principal: &str, group: &str) -> Result<(), EnokeysError> {
+    let _lock = ROLES_LOCK.lock().unwrap();
+    let mut assignments = load()?;
+    let count = assignments.len();
+    assignments.retain(|a| !(a.principal == principal && a.group == group));
+    if assignments.len() == count {
+        return Err(EnokeysError::InvalidData(format!(
+            "{} has no role in {}",
+            principal, group
+        )));
+    }
+    save(&assignments)
+}
+
+/// Removes all roles of the principal, e.g. when its account is deleted.
+pub fn revoke_all(principal: &str) -> Result<(), EnokeysError> {
+    let _lock = ROLES_LOCK.lock().unwrap();
+    let mut assignments = load()?;
+    assignments.retain(|a| a.principal != principal);
+    save(&assignments)
+}
+
+/// A logged-in account or OpenID Connect user together with its roles.
+pub struct Principal {
+    pub name: String,
+    pub roles: HashMap<String, Role>,
+}
+
+impl Principal {
+    pub fn load(name: String) -> Result<Principal, EnokeysError> {
+        let mut roles = HashMap::new();
+        for assignment in list()?.into_iter().filter(|a| a.principal == name) {
+            roles.insert(assignment.group, Role::parse(&assignment.role)?);
+        }
+        Ok(Principal { name, roles })
+    }
+
+    pub fn has_role(&self, group: &str, role: Role) -> bool {
+        self.roles.get(group).map(|r| *r >= role).unwrap_or(false)
+    }
+
+    /// The groups in which the principal has at least `role`.
+    pub fn groups(&self, role: Role) -> Vec<&'static str> {
+        GROUPS
+            .iter()
+            .cloned()
+            .filter(|g| self.has_role(g, role))
+            .collect()
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Principal {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Principal, ()> {
+        if let Some(username) = auth::session_username(request) {
+            return match Principal::load(username) {
+                Ok(principal) => Outcome::Success(principal),
+                Err(_) => Outcome::Failure((Status::InternalServerError, ())),
+            };
+        }
+        match request.guard::<OidcUser>().succeeded() {
+            // members of the OpenID Connect admin group own every group
+            Some(ref user) if user.admin => Outcome::Success(Principal {
+                name: format!("{}{}", OIDC_PREFIX, &user.username),
+                roles: GROUPS
+                    .iter()
+                    .map(|g| (g.to_string(), Role::Owner))
+                    .collect(),
+            }),
+            Some(user) => match Principal::load(format!("{}{}", OIDC_PREFIX, &user.username)) {
+                Ok(principal) => Outcome::Success(principal),
+                Err(_) => Outcome::Failure((Status::InternalServerError, ())),
+            },
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+pub trait RequiredRole {
+    const ROLE: Role;
+}
+
+pub struct ReviewerRole;
+pub struct DeployerRole;
+pub struct OwnerRole;
+
+impl RequiredRole for ReviewerRole {
+    const ROLE: Role = Role::Reviewer;
+}
+
+impl RequiredRole for DeployerRole {
+    const ROLE: Role = Role::Deployer;
+}
+
+impl RequiredRole for OwnerRole {
+    const ROLE: Role = Role::Owner;
+}
+
+/// A principal with at least the role `R` in the group named by the second
+/// path segment, all group scoped routes look like `/deploy/<group>`.
+pub struct GroupRole<R: RequiredRole> {
+    pub principal: Principal,
+    pub group: String,
+    role: PhantomData<R>,
+}
+
+impl<R: RequiredRole> GroupRole<R> {
+    pub fn admin(&self) -> bool {
+        self.group == GROUPS[0]
+    }
+}
+
+pub type Reviewer = GroupRole<ReviewerRole>;
+pub type Deployer = GroupRole<DeployerRole>;
+pub type Owner = GroupRole<OwnerRole>;
+
+impl<'a, 'r, R: RequiredRole> FromRequest<'a, 'r> for GroupRole<R> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<GroupRole<R>, ()> {
+        let group = match request.get_param::<String>(1) {
+            Some(Ok(ref group)) if check_group(group).is_ok() => group.clone(),
+            _ => return Outcome::Failure((Status::NotFound, ())),
+        };
+        let principal = match request.guard::<Principal>() {
+            Outcome::Success(principal) => principal,
+            Outcome::Failure(e) => return Outcome::Failure(e),
+            Outcome::Forward(f) => return Outcome::Forward(f),
+        };
+        if !principal.has_role(&group, R::ROLE) {
+            return Outcome::Failure((Status::Forbidden, ()));
+        }
+        Outcome::Success(GroupRole {
+            principal,
+            group,
+            role: PhantomData,
+        })
+    }
+}
@@ -1,28 +1,81 @@
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
 use error::EnokeysError;
+use ldap;
+use metrics;
 
 const GITHUB: &str = "github";
 const GITLAB: &str = "gitlab";
 const TUBLAB: &str = "tublab";
 const ENOLAB: &str = "enolab";
+const CODEBERG: &str = "codeberg";
+const SOURCEHUT: &str = "sourcehut";
 
-pub fn fetch(user: &str, provider: &str) -> Result<Vec<String>, EnokeysError> {
-    let url = get_url(&user, &provider)?;
-    if let Some(keys) = fetch_from_cache(&user, &provider) {
+/// Providers a Gitea instance must not be named after.
+const RESERVED: [&str; 8] = [
+    GITHUB,
+    GITLAB,
+    TUBLAB,
+    ENOLAB,
+    CODEBERG,
+    SOURCEHUT,
+    "raw",
+    ldap::LDAP,
+];
+
+pub const GITLAB_GROUP_SUFFIX: &str = "-group";
+pub const GITLAB_GUEST_ACCESS: u32 = 10;
+
+/// Set by `generate --cache-only`, providers are never asked.
+static CACHE_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Only answers fetches from the cache, entries that are not cached fail.
+pub fn set_cache_only() {
+    CACHE_ONLY.store(true, Ordering::Relaxed);
+}
+
+pub fn cache_only() -> bool {
+    CACHE_ONLY.load(Ordering::Relaxed)
+}
+
+fn not_cached(user: &str, provider: &str) -> EnokeysError {
+    EnokeysError::InvalidData(format!("{}@{} is not cached", user, provider))
+}
+
+enum KeySource {
+    KeysFile(String),
+    GiteaApi(String),
+}
+
+pub fn fetch(
+    user: &str,
+    provider: &str,
+    gitea_instances: &HashMap<String, String>,
+) -> Result<Vec<String>, EnokeysError> {
+    if !valid_user(user, provider) {
+        return Err(EnokeysError::InvalidData(format!(
+            "invalid {} user: {:?}",
+            provider, user
+        )));
+    }
+    let source = get_source(user, provider, gitea_instances)?;
+    if let Some(keys) = fetch_from_cache(user, provider)? {
         Ok(keys)
+    } else if cache_only() {
+        Err(not_cached(user, provider))
     } else {
-        let mut res = reqwest::get(&url)?;
-        if res.status() != 200 {
-            return Err(EnokeysError::InvalidProviderResponse);
-        }
-        let keys = res
-            .text()?
-            .split('\n')
-            .filter(|&i| !i.is_empty())
+        let start = Instant::now();
+        let lines = fetch_source(source);
+        metrics::fetch(provider, start.elapsed(), lines.is_ok());
+        let lines = lines?;
+        let keys = lines
+            .iter()
             .map(|s| format!("{} {}@{}", s, user, &provider))
             .collect::<Vec<String>>();
         save_to_cache(&user, &provider, &keys);
@@ -30,18 +83,279 @@ pub fn fetch(user: &str, provider: &str) -> Result<Vec<String>, EnokeysError> {
     }
 }
 
-fn get_url(user: &str, provider: &str) -> Result<String, EnokeysError> {
+/// Whether `user` only has characters the provider allows in account names, which keeps
+/// it out of other URL paths and the cache directory.
+fn valid_user(user: &str, provider: &str) -> bool {
+    let (user, allowed): (&str, fn(char) -> bool) = match provider {
+        GITHUB => (user, |c| c.is_ascii_alphanumeric() || c == '-'),
+        SOURCEHUT => (user.trim_start_matches('~'), |c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-'
+        }),
+        // GitLab and Gitea
+        _ => (user, |c| {
+            c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'
+        }),
+    };
+    user.len() <= 255
+        && user.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && user.chars().all(allowed)
+}
+
+fn fetch_source(source: KeySource) -> Result<Vec<String>, EnokeysError> {
+    match source {
+        KeySource::KeysFile(url) => {
+            let mut res = reqwest::get(&url)?;
+            if res.status() != 200 {
+                return Err(EnokeysError::InvalidProviderResponse);
+            }
+            Ok(res
+                .text()?
+                .split('\n')
+                .filter(|&i| !i.is_empty())
+                .map(|s| s.to_owned())
+                .collect::<Vec<String>>())
+        }
+        KeySource::GiteaApi(url) => {
+            let mut keys = vec![];
+            let mut next = Some(url);
+            while let Some(url) = next {
+                let mut res = reqwest::get(&url)?;
+                if res.status() != 200 {
+                    return Err(EnokeysError::InvalidProviderResponse);
+                }
+                next = next_link(res.headers());
+                let page = res.json::<Vec<ProviderKey>>()?;
+                if page.is_empty() {
+                    break;
+                }
+                keys.extend(page.into_iter().map(|k| k.key.trim().to_owned()));
+            }
+            Ok(keys)
+        }
+    }
+}
+
+/// The `rel="next"` URL of Gitea's `Link` header, absent on the last page.
+fn next_link(headers: &reqwest::header::HeaderMap) -> Option<String> {
+    headers
+        .get_all("Link")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .find(|link| link.contains("rel=\"next\""))
+        .and_then(|link| {
+            let start = link.find('<')? + 1;
+            let end = link.find('>')?;
+            link.get(start..end).map(|url| url.to_string())
+        })
+}
+
+fn get_source(
+    user: &str,
+    provider: &str,
+    gitea_instances: &HashMap<String, String>,
+) -> Result<KeySource, EnokeysError> {
     match provider {
-        GITHUB => Ok(format!("https://www.github.com/{}.keys", &user)),
-        GITLAB => Ok(format!("https://www.gitlab.com/{}.keys", &user)),
-        TUBLAB => Ok(format!("https://gitlab.tubit.tu-berlin.de/{}.keys", &user)),
-        ENOLAB => Ok(format!("https://gitlab.enoflag.de/{}.keys", &user)),
+        GITHUB => Ok(KeySource::KeysFile(format!(
+            "https://www.github.com/{}.keys",
+            &user
+        ))),
+        GITLAB | TUBLAB | ENOLAB => Ok(KeySource::KeysFile(format!(
+            "{}/{}.keys",
+            gitlab_base_url(provider)?,
+            &user
+        ))),
+        SOURCEHUT => Ok(KeySource::KeysFile(format!(
+            "https://meta.sr.ht/~{}.keys",
+            user.trim_start_matches('~')
+        ))),
+        CODEBERG => Ok(KeySource::GiteaApi(gitea_keys_url(
+            "https://codeberg.org",
+            user,
+        ))),
+        x => match gitea_instances.get(x) {
+            Some(base_url) => Ok(KeySource::GiteaApi(gitea_keys_url(base_url, user))),
+            None => Err(EnokeysError::InvalidProviderError(x.to_owned())),
+        },
+    }
+}
+
+/// Whether keys of `provider` can be fetched by [`fetch`].
+pub fn is_provider(provider: &str, gitea_instances: &HashMap<String, String>) -> bool {
+    get_source("", provider, gitea_instances).is_ok()
+}
+
+/// Rejects Gitea instances named like a built-in provider or a group provider.
+pub fn check_gitea_instances(instances: &HashMap<String, String>) -> Result<(), EnokeysError> {
+    for name in instances.keys() {
+        if RESERVED.contains(&name.as_str()) || name.ends_with(GITLAB_GROUP_SUFFIX) {
+            return Err(EnokeysError::InvalidData(format!(
+                "gitea instance name is reserved: {}",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn gitea_keys_url(base_url: &str, user: &str) -> String {
+    format!(
+        "{}/api/v1/users/{}/keys?limit=50",
+        base_url.trim_end_matches('/'),
+        &user
+    )
+}
+
+pub fn gitlab_base_url(instance: &str) -> Result<&'static str, EnokeysError> {
+    match instance {
+        GITLAB => Ok("https://gitlab.com"),
+        TUBLAB => Ok("https://gitlab.tubit.tu-berlin.de"),
+        ENOLAB => Ok("https://gitlab.enoflag.de"),
         x => Err(EnokeysError::InvalidProviderError(x.to_owned())),
     }
 }
 
+#[derive(Deserialize)]
+struct GitLabGroup {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+struct GitLabMember {
+    id: u64,
+    username: String,
+    access_level: u32,
+    state: String,
+}
+
+#[derive(Deserialize)]
+struct ProviderKey {
+    key: String,
+}
+
+/// Fetches the keys of all active members of a GitLab group (and its subgroups)
+/// whose access level is at least `min_access_level`.
+/// The group entry is cached like a single user under `<instance>-group`.
+pub fn fetch_gitlab_group(
+    group: &str,
+    instance: &str,
+    min_access_level: u32,
+    token: Option<&str>,
+) -> Result<Vec<String>, EnokeysError> {
+    let base_url = gitlab_base_url(instance)?;
+    let cache_provider = format!("{}{}", instance, GITLAB_GROUP_SUFFIX);
+    let cache_name = format!("{}:{}", group.replace('/', "_"), min_access_level);
+    if let Some(keys) = fetch_from_cache(&cache_name, &cache_provider)? {
+        return Ok(keys);
+    }
+    if cache_only() {
+        return Err(not_cached(group, &cache_provider));
+    }
+
+    let start = Instant::now();
+    let keys = fetch_gitlab_group_keys(base_url, group, instance, min_access_level, token);
+    metrics::fetch(&cache_provider, start.elapsed(), keys.is_ok());
+    let keys = keys?;
+    save_to_cache(&cache_name, &cache_provider, &keys);
+    Ok(keys)
+}
+
+fn fetch_gitlab_group_keys(
+    base_url: &str,
+    group: &str,
+    instance: &str,
+    min_access_level: u32,
+    token: Option<&str>,
+) -> Result<Vec<String>, EnokeysError> {
+    let group_id = group.replace('/', "%2F");
+    let mut group_ids = vec![
+        gitlab_get::<GitLabGroup>(&format!("{}/api/v4/groups/{}", base_url, &group_id), token)?.id,
+    ];
+    group_ids.extend(
+        gitlab_get_all::<GitLabGroup>(
+            &format!("{}/api/v4/groups/{}/descendant_groups", base_url, &group_id),
+            token,
+        )?
+        .iter()
+        .map(|g| g.id),
+    );
+
+    let mut members: Vec<GitLabMember> = vec![];
+    for group_id in group_ids {
+        for member in gitlab_get_all::<GitLabMember>(
+            &format!("{}/api/v4/groups/{}/members/all", base_url, group_id),
+            token,
+        )? {
+            if member.state == "active"
+                && member.access_level >= min_access_level
+                && !members.iter().any(|m| m.id == member.id)
+            {
+                members.push(member);
+            }
+        }
+    }
+
+    let mut keys = vec![];
+    for member in members {
+        for key in gitlab_get_all::<ProviderKey>(
+            &format!("{}/api/v4/users/{}/keys", base_url, member.id),
+            token,
+        )? {
+            keys.push(format!(
+                "{} {}@{}",
+                key.key.trim(),
+                member.username,
+                instance
+            ));
+        }
+    }
+    Ok(keys)
+}
+
+fn gitlab_request(url: &str, token: Option<&str>) -> Result<reqwest::Response, EnokeysError> {
+    let mut request = reqwest::Client::new().get(url);
+    if let Some(token) = token {
+        request = request.header("PRIVATE-TOKEN", token);
+    }
+    let res = request.send()?;
+    if res.status() != 200 {
+        return Err(EnokeysError::InvalidProviderResponse);
+    }
+    Ok(res)
+}
+
+fn gitlab_get<T>(url: &str, token: Option<&str>) -> Result<T, EnokeysError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    Ok(gitlab_request(url, token)?.json()?)
+}
+
+/// Follows GitLab's `X-Next-Page` pagination and collects all pages.
+fn gitlab_get_all<T>(url: &str, token: Option<&str>) -> Result<Vec<T>, EnokeysError>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let mut items = vec![];
+    let mut page = "1".to_string();
+    loop {
+        let mut res = gitlab_request(&format!("{}?per_page=100&page={}", url, page), token)?;
+        let next_page = res
+            .headers()
+            .get("X-Next-Page")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        items.extend(res.json::<Vec<T>>()?);
+        if next_page.is_empty() {
+            return Ok(items);
+        }
+        page = next_page;
+    }
+}
+
 fn save_to_cache(user: &str, provider: &str, keys: &[String]) {
-    println!("Saving keys of {}@{} to cache", &user, &provider);
+    debug!("Saving keys of {}@{} to cache", &user, &provider);
     fs::create_dir_all(format!("./.enocache/{}", &provider)).unwrap();
     if let Ok(mut file) = File::create(format!("./.enocache/{}/{}", &provider, &user)) {
         for key in keys {
@@ -49,22 +363,52 @@ fn save_to_cache(user: &str, provider: &str, keys: &[String]) {
             file.write_all("\n".as_bytes()).unwrap();
         }
     } else {
-        eprintln!("Could not create cache file")
+        error!("Could not create cache file for {}@{}", &user, &provider)
     }
 }
 
-fn fetch_from_cache(user: &str, provider: &str) -> Option<Vec<String>> {
-    if let Ok(mut file) = File::open(format!("./.enocache/{}/{}", &provider, &user)) {
+fn fetch_from_cache(user: &str, provider: &str) -> Result<Option<Vec<String>>, EnokeysError> {
+    let file = File::open(format!("./.enocache/{}/{}", &provider, &user));
+    metrics::cache_lookup(file.is_ok());
+    if let Ok(mut file) = file {
         let mut content = String::new();
-        file.read_to_string(&mut content).unwrap();
-        Some(
+        file.read_to_string(&mut content)?;
+        Ok(Some(
             content
                 .split('\n')
                 .filter(|&i| !i.is_empty())
                 .map(|s| s.to_owned())
                 .collect::<Vec<String>>(),
-        )
+        ))
     } else {
-        None
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_names_are_validated_per_provider() {
+        assert!(valid_user("alice-1", GITHUB));
+        assert!(valid_user("alice.b_c", GITLAB));
+        assert!(valid_user("alice.b_c", "gitea"));
+        assert!(valid_user("~alice_b", SOURCEHUT));
+        for user in &["", ".", "..", "../x", "a/b", "alice.b", "-alice"] {
+            assert!(!valid_user(user, GITHUB), "{:?} was accepted", user);
+        }
+        for user in &[".", "..", ".x", "a/b", "a%2f"] {
+            assert!(!valid_user(user, GITLAB), "{:?} was accepted", user);
+        }
+        assert!(!valid_user("~Alice", SOURCEHUT));
+    }
+
+    #[test]
+    fn invalid_users_are_not_fetched() {
+        match fetch("..", GITHUB, &HashMap::new()) {
+            Err(EnokeysError::InvalidData(message)) => assert!(message.contains("invalid")),
+            _ => panic!("fetched an invalid user"),
+        }
     }
 }
@@ -1,36 +1,106 @@
 extern crate dirs;
 use std::cmp::min;
+use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
 use openssh_keys::PublicKey;
 
 use error::EnokeysError;
+use ldap;
+use metrics;
+use roles;
 use scraper;
+use Context;
 use ADMIN_DESTINATIONS_AUTHORIZED_KEYS;
 use ADMIN_DESTINATIONS_STORAGE_PROVIDERS;
 use ADMIN_DESTINATIONS_STORAGE_RAW;
+use GROUPNAME_REGEX;
 use USERNAME_REGEX;
 use USER_DESTINATIONS_AUTHORIZED_KEYS;
 use USER_DESTINATIONS_STORAGE_PROVIDERS;
 use USER_DESTINATIONS_STORAGE_RAW;
 
-pub fn handle_raw_submission(name: &str, pub_key: &str, admin: bool) -> Result<(), EnokeysError> {
-    let raw_storage: &PathBuf = if admin {
-        &ADMIN_DESTINATIONS_STORAGE_RAW
+lazy_static! {
+    static ref PENDING_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// The outcome of generating one authorized_keys file.
+pub struct Generated {
+    pub path: PathBuf,
+    pub keys: usize,
+    /// Entries that could not be fetched and keys that could not be parsed,
+    /// they are left out of the file.
+    pub failures: Vec<String>,
+}
+
+/// A submission in a group's storage or waiting for approval by a reviewer.
+#[derive(Serialize)]
+pub struct Entry {
+    pub raw: bool,
+    pub entry: String,
+}
+
+fn storage_paths(admin: bool) -> (&'static PathBuf, &'static PathBuf) {
+    if admin {
+        (
+            &ADMIN_DESTINATIONS_STORAGE_RAW,
+            &ADMIN_DESTINATIONS_STORAGE_PROVIDERS,
+        )
     } else {
-        &USER_DESTINATIONS_STORAGE_RAW
+        (
+            &USER_DESTINATIONS_STORAGE_RAW,
+            &USER_DESTINATIONS_STORAGE_PROVIDERS,
+        )
+    }
+}
+
+fn pending_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(".pending");
+    PathBuf::from(path)
+}
+
+/// Parses a submitted raw key and re-encodes it without its comment, so a submission
+/// is exactly one key.
+pub fn parse_raw_key(pub_key: &str) -> Result<String, EnokeysError> {
+    // ssh-key keeps everything after the blob as the comment, line breaks included
+    if pub_key.contains(['\r', '\n']) {
+        return Err(EnokeysError::InvalidData(
+            "raw key spans several lines".to_string(),
+        ));
+    }
+    let mut key = ssh_key::PublicKey::from_openssh(pub_key.trim())?;
+    key.set_comment("");
+    Ok(key.to_openssh()?)
+}
+
+pub fn handle_raw_submission(
+    name: &str,
+    pub_key: &str,
+    admin: bool,
+    pending: bool,
+) -> Result<(), EnokeysError> {
+    let pub_key = parse_raw_key(pub_key)?;
+    let (raw_storage, _) = storage_paths(admin);
+    let raw_storage = if pending {
+        pending_path(raw_storage)
+    } else {
+        raw_storage.clone()
     };
     let name = USERNAME_REGEX.replace_all(name, "_");
+    let _lock = PENDING_LOCK.lock().unwrap();
     let raw_storage_file = OpenOptions::new()
         .write(true)
         .create(true)
         .append(true)
         .open(raw_storage)?;
     writeln!(&raw_storage_file, "{} {}@raw", &pub_key, &name)?;
+    metrics::submission(roles::group_name(admin), "raw");
     Ok(())
 }
 
@@ -39,6 +109,7 @@ pub fn handle_submission(
     user_name: &str,
     name: &str,
     admin: bool,
+    pending: bool,
 ) -> Result<(), EnokeysError> {
     if provider.is_empty() || user_name.is_empty() {
         return Err(EnokeysError::InvalidData(
@@ -46,28 +117,227 @@ pub fn handle_submission(
         ));
     }
     let user_name = USERNAME_REGEX.replace_all(user_name, "");
+    store_provider_entry(provider, &user_name, name, admin, pending)
+}
+
+pub fn handle_group_submission(
+    instance: &str,
+    group: &str,
+    min_access_level: u32,
+    name: &str,
+    admin: bool,
+    pending: bool,
+) -> Result<(), EnokeysError> {
+    scraper::gitlab_base_url(instance)?;
+    let group = GROUPNAME_REGEX.replace_all(group.trim_matches('/'), "");
+    if group.is_empty() {
+        return Err(EnokeysError::InvalidData("group empty".to_string()));
+    }
+    store_provider_entry(
+        &format!("{}{}", instance, scraper::GITLAB_GROUP_SUFFIX),
+        &format!("{}:{}", group, min_access_level),
+        name,
+        admin,
+        pending,
+    )
+}
+
+fn store_provider_entry(
+    provider: &str,
+    entry: &str,
+    name: &str,
+    admin: bool,
+    pending: bool,
+) -> Result<(), EnokeysError> {
     let name = USERNAME_REGEX.replace_all(name, " ");
-    let file_path: &PathBuf = if admin {
-        &ADMIN_DESTINATIONS_STORAGE_PROVIDERS
+    let (_, file_path) = storage_paths(admin);
+    let file_path = if pending {
+        pending_path(file_path)
     } else {
-        &USER_DESTINATIONS_STORAGE_PROVIDERS
+        file_path.clone()
     };
+    let _lock = PENDING_LOCK.lock().unwrap();
     let mut storage_file = OpenOptions::new()
         .write(true)
         .create(true)
         .append(true)
         .open(&file_path)?;
-    let line = format!("# {} \n{}:{}\n", &name, provider, &user_name);
-    println!("Adding entry:\n{}", &line);
+    let line = format!("# {} \n{}:{}\n", &name, provider, &entry);
+    info!(name = %name, provider, pending, "adding entry");
+    debug!("Adding entry:\n{}", &line);
     write!(storage_file, "{}", &line)?;
+    metrics::submission(roles::group_name(admin), provider);
     Ok(())
 }
 
+/// Splits a providers file into its `# name` comment and entry line pairs.
+fn provider_blocks(content: &str) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut comment = String::new();
+    for line in content.split('\n').filter(|l| !l.is_empty()) {
+        if line.starts_with('#') {
+            comment = format!("{}\n", line);
+        } else {
+            blocks.push(format!("{}{}\n", &comment, line));
+            comment.clear();
+        }
+    }
+    blocks
+}
+
+fn read_file(path: &Path) -> Result<String, EnokeysError> {
+    let mut content = String::new();
+    if let Ok(mut file) = File::open(path) {
+        file.read_to_string(&mut content)?;
+    }
+    Ok(content)
+}
+
+fn raw_lines(content: &str) -> Vec<String> {
+    content
+        .split('\n')
+        .filter(|l| !l.is_empty())
+        .map(|l| format!("{}\n", l))
+        .collect()
+}
+
+fn read_entries(raw_storage: &Path, providers_storage: &Path) -> Result<Vec<Entry>, EnokeysError> {
+    let mut entries: Vec<Entry> = raw_lines(&read_file(raw_storage)?)
+        .into_iter()
+        .map(|entry| Entry { raw: true, entry })
+        .collect();
+    entries.extend(
+        provider_blocks(&read_file(providers_storage)?)
+            .into_iter()
+            .map(|entry| Entry { raw: false, entry }),
+    );
+    Ok(entries)
+}
+
+/// The approved entries of the group.
+pub fn entries(admin: bool) -> Result<Vec<Entry>, EnokeysError> {
+    let (raw_storage, providers_storage) = storage_paths(admin);
+    let _lock = PENDING_LOCK.lock().unwrap();
+    read_entries(raw_storage, providers_storage)
+}
+
+pub fn pending_entries(admin: bool) -> Result<Vec<Entry>, EnokeysError> {
+    let (raw_storage, providers_storage) = storage_paths(admin);
+    let _lock = PENDING_LOCK.lock().unwrap();
+    read_entries(&pending_path(raw_storage), &pending_path(providers_storage))
+}
+
+/// Whether a line of the entry, or a word in one, equals `pattern`,
+/// e.g. `github:alice`, a key's base64 blob or `bob@raw`.
+pub fn entry_matches(entry: &str, pattern: &str) -> bool {
+    entry
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .any(|l| l.trim() == pattern || l.split_whitespace().any(|w| w == pattern))
+}
+
+/// Removes the group's approved entries matching `pattern` and returns them.
+pub fn revoke_entries(admin: bool, pattern: &str) -> Result<Vec<Entry>, EnokeysError> {
+    let (raw_storage, providers_storage) = storage_paths(admin);
+    let _lock = PENDING_LOCK.lock().unwrap();
+    let mut revoked = vec![];
+    for (storage, raw) in &[(raw_storage, true), (providers_storage, false)] {
+        let content = read_file(storage)?;
+        let entries = if *raw {
+            raw_lines(&content)
+        } else {
+            provider_blocks(&content)
+        };
+        let (matching, kept): (Vec<String>, Vec<String>) =
+            entries.into_iter().partition(|e| entry_matches(e, pattern));
+        if matching.is_empty() {
+            continue;
+        }
+        let mut file = File::create(storage)?;
+        write!(file, "{}", kept.concat())?;
+        revoked.extend(matching.into_iter().map(|entry| Entry { raw: *raw, entry }));
+    }
+    Ok(revoked)
+}
+
+/// Removes a pending entry and, if approved, adds it to the group's storage.
+pub fn review_entry(
+    admin: bool,
+    raw: bool,
+    entry: &str,
+    approve: bool,
+) -> Result<(), EnokeysError> {
+    let (raw_storage, providers_storage) = storage_paths(admin);
+    let storage = if raw { raw_storage } else { providers_storage };
+    let _lock = PENDING_LOCK.lock().unwrap();
+    let content = read_file(&pending_path(storage))?;
+    let mut entries: Vec<String> = if raw {
+        raw_lines(&content)
+    } else {
+        provider_blocks(&content)
+    };
+    // browsers submit form fields with CRLF line endings
+    let entry = entry.replace("\r\n", "\n");
+    let position = match entries.iter().position(|e| *e == entry) {
+        Some(position) => position,
+        None => {
+            return Err(EnokeysError::InvalidData(
+                "no such pending entry".to_string(),
+            ))
+        }
+    };
+    let entry = entries.remove(position);
+    if approve {
+        let mut storage_file = OpenOptions::new().create(true).append(true).open(storage)?;
+        debug!("Approved entry:\n{}", &entry);
+        write!(storage_file, "{}", &entry)?;
+    }
+    let mut pending_file = File::create(pending_path(storage))?;
+    write!(pending_file, "{}", entries.concat())?;
+    Ok(())
+}
+
+fn fetch_entry(config: &Context, entry: &[&str]) -> Result<Vec<String>, EnokeysError> {
+    match entry[0] {
+        ldap::LDAP | ldap::LDAP_GROUP => {
+            if scraper::cache_only() {
+                return Err(EnokeysError::InvalidData(format!(
+                    "{}@{} is not cached",
+                    entry[1], entry[0]
+                )));
+            }
+            let ldap_config = match config.ldap {
+                Some(ref ldap_config) => ldap_config,
+                None => return Err(EnokeysError::InvalidProviderError(entry[0].to_string())),
+            };
+            if entry[0] == ldap::LDAP {
+                ldap::fetch_user(ldap_config, entry[1])
+            } else {
+                ldap::fetch_group(ldap_config, entry[1])
+            }
+        }
+        provider => match provider.strip_suffix(scraper::GITLAB_GROUP_SUFFIX) {
+            Some(instance) => scraper::fetch_gitlab_group(
+                entry[1],
+                instance,
+                entry
+                    .get(2)
+                    .and_then(|l| l.parse().ok())
+                    .unwrap_or(scraper::GITLAB_GUEST_ACCESS),
+                config.gitlab_tokens.get(instance).map(|t| t.as_str()),
+            ),
+            None => scraper::fetch(entry[1], entry[0], &config.gitea_instances),
+        },
+    }
+}
+
 fn generate_authorized_key_file(
-    authorized_keys_file_name: &PathBuf,
+    config: &Context,
+    authorized_keys_file_name: &Path,
     providers_storage_file_names: &[&PathBuf],
     raw_storage_file_names: &[&PathBuf],
-) -> Result<(), EnokeysError> {
+) -> Result<Generated, EnokeysError> {
+    let mut failures = vec![];
     let mut authorized_keys_file = File::create(&authorized_keys_file_name)?;
 
     // append deploy key
@@ -76,7 +346,7 @@ fn generate_authorized_key_file(
     let mut path = dirs::home_dir().unwrap();
     path.push(".ssh");
     path.push("id_ed25519.pub");
-    println!("Loading SSH-pubkeyfile {:?}", path);
+    debug!("Loading SSH-pubkeyfile {:?}", path);
     if let Ok(mut deploy_key_file) = File::open(path) {
         deploy_key_file.read_to_string(&mut deploy_key)?;
         write!(authorized_keys_file, "{}", &deploy_key)?
@@ -106,15 +376,16 @@ fn generate_authorized_key_file(
             .filter(|&s| &s[0..1] != "#")
         {
             let entry = line.split(':').collect::<Vec<&str>>();
-            let user_keys = match scraper::fetch(entry[1], entry[0]) {
+            let user_keys = match fetch_entry(config, &entry) {
                 Ok(user_keys) => user_keys,
                 Err(e) => {
-                    println!("could not fetch {} ({:?})", &line, &e);
+                    warn!("could not fetch {} ({:?})", &line, &e);
+                    failures.push(format!("could not fetch {} ({:?})", line, e));
                     continue;
                 }
             };
             for key in user_keys {
-                println!("parsing key: {}", &key);
+                trace!("parsing key: {}", &key);
                 match PublicKey::parse(&key) {
                     Ok(key) => match &key.comment {
                         Some(ref comment) => {
@@ -136,22 +407,57 @@ fn generate_authorized_key_file(
                             base64::encode(&key.data())
                         )?,
                     },
-                    Err(e) => println!("Failed to parse PublicKey: {:?}", e),
+                    Err(e) => {
+                        warn!("Failed to parse PublicKey: {:?}", e);
+                        failures.push(format!("invalid key from {} ({:?})", line, e));
+                    }
                 }
             }
         }
     }
-    Ok(())
+    let keys = read_file(authorized_keys_file_name)?
+        .lines()
+        .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+        .count();
+    metrics::authorized_keys(
+        &authorized_keys_file_name
+            .file_name()
+            .unwrap()
+            .to_string_lossy(),
+        keys as u64,
+    );
+    Ok(Generated {
+        path: authorized_keys_file_name.to_path_buf(),
+        keys,
+        failures,
+    })
+}
+
+pub fn generate_authorized_key_files(config: &Context) -> Result<Vec<Generated>, EnokeysError> {
+    generate_authorized_key_files_in(config, None)
 }
 
-pub fn generate_authorized_key_files() -> Result<(), EnokeysError> {
-    generate_authorized_key_file(
-        &ADMIN_DESTINATIONS_AUTHORIZED_KEYS,
+/// Writes the authorized_keys files into `output` instead of `./keyfiles`.
+pub fn generate_authorized_key_files_in(
+    config: &Context,
+    output: Option<&Path>,
+) -> Result<Vec<Generated>, EnokeysError> {
+    let path = |file: &PathBuf| match output {
+        Some(output) => output.join(file.file_name().unwrap()),
+        None => file.to_path_buf(),
+    };
+    if let Some(output) = output {
+        fs::create_dir_all(output)?;
+    }
+    let admin = generate_authorized_key_file(
+        config,
+        &path(&ADMIN_DESTINATIONS_AUTHORIZED_KEYS),
         &[&ADMIN_DESTINATIONS_STORAGE_PROVIDERS],
         &[&ADMIN_DESTINATIONS_STORAGE_RAW],
     )?;
-    generate_authorized_key_file(
-        &USER_DESTINATIONS_AUTHORIZED_KEYS,
+    let user = generate_authorized_key_file(
+        config,
+        &path(&USER_DESTINATIONS_AUTHORIZED_KEYS),
         &[
             &ADMIN_DESTINATIONS_STORAGE_PROVIDERS,
             &USER_DESTINATIONS_STORAGE_PROVIDERS,
@@ -161,14 +467,19 @@ pub fn generate_authorized_key_files() -> Result<(), EnokeysError> {
             &USER_DESTINATIONS_STORAGE_RAW,
         ],
     )?;
-    Ok(())
+    Ok(vec![admin, user])
 }
 
-pub fn load_deploy_keypair() -> Result<(), EnokeysError> {
+pub fn deploy_key_path() -> PathBuf {
     let mut path = dirs::home_dir().unwrap();
     path.push(".ssh");
     path.push("id_ed25519");
-    println!("Loading SSH-keyfile {:?}", path);
+    path
+}
+
+pub fn load_deploy_keypair() -> Result<(), EnokeysError> {
+    let path = deploy_key_path();
+    info!("Loading SSH-keyfile {:?}", path);
     // TODO: User-configable ssh key
     match File::open(path) {
         Ok(_) => Ok(()),
@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+
+use auth;
+use challenge;
+use error::EnokeysError;
+use API_TOKENS_STORAGE;
+
+lazy_static! {
+    static ref TOKENS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// An API token acting as `principal`; only the token's hash is stored.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ApiToken {
+    principal: String,
+    hash: String,
+    created: DateTime<Utc>,
+}
+
+fn hash(token: &str) -> String {
+    base64::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn load() -> Result<Vec<ApiToken>, EnokeysError> {
+    let mut content = String::new();
+    match File::open(&*API_TOKENS_STORAGE) {
+        Ok(mut file) => file.read_to_string(&mut content)?,
+        Err(_) => return Ok(vec![]),
+    };
+    Ok(serde_json::from_str(&content)?)
+}
+
+fn save(tokens: &[ApiToken]) -> Result<(), EnokeysError> {
+    let mut file = File::create(&*API_TOKENS_STORAGE)?;
+    file.write_all(serde_json::to_string_pretty(tokens)?.as_bytes())?;
+    Ok(())
+}
+
+/// Creates a token for the principal and returns it, it cannot be shown again.
+pub fn create(principal: &str) -> Result<String, EnokeysError> {
+    if principal.is_empty() {
+        return Err(EnokeysError::InvalidData("invalid principal".to_string()));
+    }
+    let _lock = TOKENS_LOCK.lock().unwrap();
+    let mut tokens = load()?;
+    let token = challenge::generate_nonce();
+    tokens.push(ApiToken {
+        principal: principal.to_string(),
+        hash: hash(&token),
+        created: Utc::now(),
+    });
+    save(&tokens)?;
+    Ok(token)
+}
+
+/// Revokes all tokens of the principal and returns how many there were.
+pub fn revoke(principal: &str) -> Result<usize, EnokeysError> {
+    let _lock = TOKENS_LOCK.lock().unwrap();
+    let mut tokens = load()?;
+    let count = tokens.len();
+    tokens.retain(|t| t.principal != principal);
+    save(&tokens)?;
+    Ok(count - tokens.len())
+}
+
+/// The principal the token acts as.
+pub fn principal(token: &str) -> Option<String> {
+    let _lock = TOKENS_LOCK.lock().unwrap();
+    let hash = hash(token);
+    load()
+        .ok()?
+        .into_iter()
+        .find(|t| auth::constant_time_eq(&t.hash, &hash))
+        .map(|t| t.principal)
+}